@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::interface::{RequestMessage, ResponseError, ResponseValue, StructureLeaf, StructurePath};
+use crate::{CommandError, Error, Manager};
+
+// The device's configuration structure along with the value of every leaf,
+// arranged in the same shape as the structure
+#[derive(Debug, Clone)]
+pub struct ConfigSnapshot {
+  pub structure: ResponseValue,
+  pub values: ResponseValue,
+}
+
+// A leaf that loading a configuration would change
+#[derive(Debug, Clone)]
+pub struct ConfigChange {
+  pub path: StructurePath,
+  pub current: ResponseValue,
+  // Converted to the type of the current value
+  pub wanted: ResponseValue,
+}
+
+// The result of checking a configuration, e.g. one read back from a file,
+// against a snapshot of the device. Paths are in sorted order.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigComparison {
+  pub changes: Vec<ConfigChange>,
+  // Paths that don't fit the device's structure, with the reason. Nothing
+  // should be loaded while there are any.
+  pub mismatches: Vec<(StructurePath, String)>,
+  // Leaves with no value to load, either absent or unreadable when saved,
+  // which are left as they are
+  pub missing: Vec<StructurePath>,
+  pub unchanged: usize,
+}
+
+// A map's fields in name order, as the device's maps have none
+pub fn sorted_fields<T>(fields: &HashMap<String, T>) -> Vec<(&String, &T)> {
+  let mut fields : Vec<_> = fields.iter().collect();
+  fields.sort_by(|a, b| a.0.cmp(b.0));
+  fields
+}
+
+fn child<'a>(node: Option<&'a ResponseValue>, field: &str) -> Option<&'a ResponseValue> {
+  match node {
+    Some(ResponseValue::Map(m)) => m.get(field),
+    _ => None,
+  }
+}
+
+fn element(node: Option<&ResponseValue>, index: usize) -> Option<&ResponseValue> {
+  match node {
+    Some(ResponseValue::Array(a)) => a.get(index),
+    _ => None,
+  }
+}
+
+// Loaded maps that happen to have the fields of an output come back as
+// `Output`, but are compared field by field like any other map
+fn fields_of(value: &ResponseValue) -> Option<HashMap<String, ResponseValue>> {
+  match value {
+    ResponseValue::Map(m) => Some(m.clone()),
+    ResponseValue::Output(_) => serde_json::to_value(value).ok()
+      .and_then(|v| serde_json::from_value(v).ok()),
+    _ => None,
+  }
+}
+
+// `wanted` as the same type of value as `current`, if it can be without
+// losing anything. A whole number saved as a float still loads into an
+// integer leaf.
+fn conform(wanted: &ResponseValue, current: Option<&ResponseValue>) -> Option<ResponseValue> {
+  match (current, wanted) {
+    (Some(ResponseValue::Int(_)), ResponseValue::Float(v))
+      if v.fract() == 0.0 && *v >= 0.0 && *v <= u32::MAX as f32 => Some(ResponseValue::Int(*v as u32)),
+    (Some(ResponseValue::Float(_)), ResponseValue::Int(v)) => Some(ResponseValue::Float(*v as f32)),
+    // Nothing to check the type against
+    (None | Some(ResponseValue::Error(_)), wanted) => Some(wanted.clone()),
+    (Some(current), wanted) if std::mem::discriminant(current) == std::mem::discriminant(wanted) =>
+      Some(wanted.clone()),
+    _ => None,
+  }
+}
+
+impl ConfigSnapshot {
+  // Every leaf of the structure with its value, in sorted path order
+  pub fn leaves(&self) -> Vec<(StructurePath, &ResponseValue)> {
+    let mut leaves = vec![];
+    ConfigSnapshot::collect_leaves(&self.structure, Some(&self.values), StructurePath::new(), &mut leaves);
+    leaves
+  }
+
+  fn collect_leaves<'a>(node: &ResponseValue, values: Option<&'a ResponseValue>, path: StructurePath,
+                        leaves: &mut Vec<(StructurePath, &'a ResponseValue)>) {
+    match node {
+      ResponseValue::Map(m) => for (field, node) in sorted_fields(m) {
+        ConfigSnapshot::collect_leaves(node, child(values, field), path.clone().add_str(field), leaves);
+      },
+      ResponseValue::Array(a) => for (i, node) in a.iter().enumerate() {
+        ConfigSnapshot::collect_leaves(node, element(values, i), path.clone().add_index(i as u32), leaves);
+      },
+      ResponseValue::Leaf(_) => if let Some(value) = values {
+        leaves.push((path, value));
+      },
+      _ => (),
+    }
+  }
+
+  // Checks `wanted`, a tree shaped like `values`, against the structure and
+  // works out which leaves it would change
+  pub fn compare(&self, wanted: &ResponseValue) -> ConfigComparison {
+    let mut comparison = ConfigComparison::default();
+    ConfigSnapshot::compare_node(&self.structure, Some(&self.values), wanted, StructurePath::new(), &mut comparison);
+    comparison
+  }
+
+  fn compare_node(node: &ResponseValue, current: Option<&ResponseValue>, wanted: &ResponseValue,
+                  path: StructurePath, comparison: &mut ConfigComparison) {
+    match (node, wanted) {
+      (ResponseValue::Map(fields), wanted) => {
+        let Some(wanted_fields) = fields_of(wanted) else {
+          comparison.mismatches.push((path, "expected a map".to_string()));
+          return;
+        };
+        for (field, node) in sorted_fields(fields) {
+          let path = path.clone().add_str(field);
+          match wanted_fields.get(field) {
+            Some(wanted) => ConfigSnapshot::compare_node(node, child(current, field), wanted, path, comparison),
+            None => ConfigSnapshot::missing_leaves(node, path, comparison),
+          }
+        }
+        for (field, _) in sorted_fields(&wanted_fields) {
+          if !fields.contains_key(field) {
+            comparison.mismatches.push((path.clone().add_str(field), "not in the device's structure".to_string()));
+          }
+        }
+      },
+      (ResponseValue::Array(elements), ResponseValue::Array(wanted_elements)) => {
+        for (i, node) in elements.iter().enumerate() {
+          let path = path.clone().add_index(i as u32);
+          match wanted_elements.get(i) {
+            Some(wanted) => ConfigSnapshot::compare_node(node, element(current, i), wanted, path, comparison),
+            None => ConfigSnapshot::missing_leaves(node, path, comparison),
+          }
+        }
+        for i in elements.len()..wanted_elements.len() {
+          comparison.mismatches.push((path.clone().add_index(i as u32),
+            format!("the device only has {} elements", elements.len())));
+        }
+      },
+      (ResponseValue::Array(_), _) => comparison.mismatches.push((path, "expected an array".to_string())),
+      (ResponseValue::Leaf(leaf), wanted) => ConfigSnapshot::compare_leaf(leaf, current, wanted, path, comparison),
+      // Fixed parts of the structure have nothing to load
+      _ => (),
+    }
+  }
+
+  fn compare_leaf(leaf: &StructureLeaf, current: Option<&ResponseValue>, wanted: &ResponseValue,
+                  path: StructurePath, comparison: &mut ConfigComparison) {
+    if let ResponseValue::Error(_) = wanted {
+      comparison.missing.push(path);
+      return;
+    }
+    match conform(wanted, current) {
+      Some(wanted) if Some(&wanted) == current => comparison.unchanged += 1,
+      Some(wanted) => comparison.changes.push(ConfigChange {
+        path,
+        current: current.cloned().unwrap_or(ResponseValue::None),
+        wanted,
+      }),
+      None => comparison.mismatches.push((path, format!("expected a {} value, found {}",
+        leaf.leaf_type, serde_json::to_string(wanted).unwrap_or_default()))),
+    }
+  }
+
+  fn missing_leaves(node: &ResponseValue, path: StructurePath, comparison: &mut ConfigComparison) {
+    // Only the paths are wanted, so the structure stands in for the values
+    let mut leaves = vec![];
+    ConfigSnapshot::collect_leaves(node, Some(node), path, &mut leaves);
+    comparison.missing.extend(leaves.into_iter().map(|(path, _)| path));
+  }
+}
+
+impl Manager {
+  // Fetches the structure and then every leaf in it. A leaf the device
+  // refuses to read is recorded as a `ResponseValue::Error` rather than
+  // failing the whole snapshot. `timeout` applies to each request.
+  pub fn fetch_config(&self, timeout: Duration) -> Result<ConfigSnapshot, Error> {
+    let structure = self.request_blocking(RequestMessage::Structure{id: 0}, timeout)?;
+    let values = self.fetch_values(&structure, StructurePath::new(), timeout)?;
+    Ok(ConfigSnapshot { structure, values })
+  }
+
+  fn fetch_values(&self, node: &ResponseValue, path: StructurePath, timeout: Duration)
+    -> Result<ResponseValue, Error> {
+    Ok(match node {
+      ResponseValue::Map(m) => ResponseValue::Map(m.iter()
+        .map(|(field, child)| Ok((field.clone(), self.fetch_values(child, path.clone().add_str(field), timeout)?)))
+        .collect::<Result<_, Error>>()?),
+      ResponseValue::Array(a) => ResponseValue::Array(a.iter().enumerate()
+        .map(|(i, child)| self.fetch_values(child, path.clone().add_index(i as u32), timeout))
+        .collect::<Result<_, Error>>()?),
+      ResponseValue::Leaf(_) => match self.request_blocking(RequestMessage::Get{id: 0, path}, timeout) {
+        Ok(value) => value,
+        Err(Error::Command(CommandError::Rejected(error))) => ResponseValue::Error(ResponseError{error}),
+        Err(e) => return Err(e),
+      },
+      other => other.clone(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread;
+  use crate::connection::{self, SimulatedConfig, SimulatedConnection};
+
+  const WAIT: Duration = Duration::from_secs(2);
+
+  fn value(json: serde_json::Value) -> ResponseValue {
+    serde_json::from_value(json).unwrap()
+  }
+
+  fn paths(paths: &[StructurePath]) -> Vec<String> {
+    paths.iter().map(|p| p.to_string()).collect()
+  }
+
+  fn leaf() -> serde_json::Value {
+    serde_json::json!({"_type": "float", "description": ""})
+  }
+
+  #[test]
+  fn leaves_are_in_sorted_path_order() {
+    let snapshot = ConfigSnapshot {
+      structure: value(serde_json::json!({"b": leaf(), "a": [leaf(), leaf()], "c": leaf()})),
+      // c couldn't be read back at all
+      values: value(serde_json::json!({"b": 1.5, "a": [2.5, 3.5]})),
+    };
+    let leaves : Vec<_> = snapshot.leaves().into_iter().map(|(path, value)| (path.to_string(), value.clone())).collect();
+    assert_eq!(leaves, [
+      ("a[0]".to_string(), ResponseValue::Float(2.5)),
+      ("a[1]".to_string(), ResponseValue::Float(3.5)),
+      ("b".to_string(), ResponseValue::Float(1.5)),
+    ]);
+  }
+
+  #[test]
+  fn compare_sorts_leaves_into_changes_mismatches_and_missing() {
+    let g = Manager::new(Box::new(SimulatedConnection::new(SimulatedConfig::default()).unwrap()));
+    let snapshot = g.fetch_config(WAIT).unwrap();
+    let mut wanted = serde_json::to_value(&snapshot.values).unwrap();
+    // Whole numbers saved as floats still fit integer leaves, and the other
+    // way around
+    wanted["decoder"]["rpm_window_size"] = serde_json::json!(8.0);
+    wanted["decoder"]["offset"] = serde_json::json!(50);
+    wanted["fueling"]["injections_per_cycle"] = serde_json::json!("two");
+    wanted["fueling"]["injector_cc"] = serde_json::json!({"error": "unreadable"});
+    wanted["ignition"].as_object_mut().unwrap().remove("dwell_us");
+    wanted["ignition"]["spark_plugs"] = serde_json::json!(4);
+    wanted["outputs"][0]["pin"] = serde_json::json!(7);
+    let extra = wanted["outputs"][3].clone();
+    wanted["outputs"].as_array_mut().unwrap().push(extra);
+    let wanted = value(wanted);
+    // An output's fields decode as a whole `Output`
+    assert!(matches!(&wanted, ResponseValue::Map(m) if matches!(&m["outputs"],
+      ResponseValue::Array(a) if matches!(a[0], ResponseValue::Output(_)))));
+
+    let comparison = snapshot.compare(&wanted);
+    let changes : Vec<_> = comparison.changes.iter()
+      .map(|c| (c.path.to_string(), c.current.clone(), c.wanted.clone())).collect();
+    assert_eq!(changes, [
+      ("decoder.offset".to_string(), ResponseValue::Float(45.0), ResponseValue::Float(50.0)),
+      ("outputs[0].pin".to_string(), ResponseValue::Int(0), ResponseValue::Int(7)),
+    ]);
+    let mismatches : Vec<_> = comparison.mismatches.iter().map(|(path, _)| path.clone()).collect();
+    assert_eq!(paths(&mismatches), ["fueling.injections_per_cycle", "ignition.spark_plugs", "outputs[4]"]);
+    assert_eq!(paths(&comparison.missing), ["fueling.injector_cc", "ignition.dwell_us"]);
+    assert_eq!(comparison.unchanged, snapshot.leaves().len() - 5);
+  }
+
+  #[test]
+  fn compare_rejects_the_wrong_shape() {
+    let snapshot = ConfigSnapshot {
+      structure: value(serde_json::json!({"table": [leaf()]})),
+      values: value(serde_json::json!({"table": [1.0]})),
+    };
+    let comparison = snapshot.compare(&value(serde_json::json!({"table": {"x": 1.0}})));
+    assert_eq!(comparison.mismatches.len(), 1);
+    assert_eq!(comparison.mismatches[0].1, "expected an array");
+    assert_eq!(snapshot.compare(&value(serde_json::json!([1.0]))).mismatches[0].1, "expected a map");
+  }
+
+  #[test]
+  fn refused_leaves_are_recorded_as_errors() {
+    let (conn, device) = connection::testing::pair();
+    let g = Manager::new(Box::new(conn));
+    let fetch = thread::spawn(move || g.fetch_config(WAIT));
+
+    let structure = value(serde_json::json!({"a": leaf(), "b": leaf()}));
+    let Some(RequestMessage::Structure{id}) = device.request(WAIT) else { panic!("expected a structure request") };
+    device.respond(id, structure.clone());
+    for _ in 0..2 {
+      let Some(RequestMessage::Get{id, path}) = device.request(WAIT) else { panic!("expected a get request") };
+      let response = match path.to_string().as_str() {
+        "a" => ResponseValue::Float(1.0),
+        _ => ResponseValue::Error(ResponseError{error: "write only".to_string()}),
+      };
+      device.respond(id, response);
+    }
+
+    let snapshot = fetch.join().unwrap().unwrap();
+    assert_eq!(snapshot.structure, structure);
+    assert_eq!(snapshot.values, value(serde_json::json!({"a": 1.0, "b": {"error": "write only"}})));
+  }
+}
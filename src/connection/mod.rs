@@ -1,18 +1,50 @@
 mod usb;
 mod udp;
+mod serial;
+mod tcp;
+mod replay;
+mod simulated;
+mod stream;
+mod uri;
+#[cfg(test)]
+pub(crate) mod testing;
 
 use std::time::{Duration, SystemTime};
 use std::sync::mpsc;
 use crate::interface;
+use crate::Error;
 
-pub use usb::UsbConnection;
+pub use usb::{OpenedDevice, UsbConnection, UsbConnectionBuilder, UsbDeviceInfo};
 pub use udp::UdpConnection;
+pub use serial::SerialConnection;
+pub use tcp::TcpConnection;
+pub use replay::{ReplayConnection, ReplaySpeed};
+pub use simulated::{SimulatedConnection, SimulatedConfig};
+pub use uri::{open, ConnectionUri};
 
 pub struct RxMessage {
     pub time: SystemTime,
     pub payload: interface::Message,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkStatus {
+    Connected,
+    Disconnected,
+    // The device is back and is being reopened
+    Reconnecting,
+}
+
+// Connections that can lose and regain the device between messages report
+// it with `Link`. `Error` carries failures from a connection's background
+// threads that it has recovered from, such as an undecodable packet.
+pub enum RxEvent {
+    Message(RxMessage),
+    Link(LinkStatus),
+    Error(Error),
+}
+
+// `Disconnected` means the connection has shut down for good
 pub enum ConnError {
     Timeout,
     Disconnected,
@@ -33,12 +65,12 @@ pub struct Writer {
 }
 
 impl Writer {
-    pub fn send(&self, msg: interface::Message) {
-        self.tx.send(msg).unwrap();
+    pub fn send(&self, msg: interface::Message) -> Result<(), Error> {
+        self.tx.send(msg).map_err(|_| Error::Disconnected)
     }
 }
 
 pub trait Connection {
-    fn recv(&self, timeout: Duration) -> Result<RxMessage, ConnError>;
+    fn recv(&self, timeout: Duration) -> Result<RxEvent, ConnError>;
     fn get_writer(&self) -> Writer;
 }
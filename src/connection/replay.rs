@@ -0,0 +1,185 @@
+use std::thread;
+use std::sync::{mpsc, atomic, Arc};
+use std::time::{SystemTime, Duration, Instant};
+
+use crate::interface;
+use crate::connection;
+use crate::LogReader;
+use crate::Error;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ReplaySpeed {
+    // Multiple of the recorded rate, 1.0 is real time. Must be positive.
+    Scale(f64),
+    AsFastAsPossible,
+}
+
+// Plays back a log written by `LogFeedWriter` as if it were a live ECU: a
+// Description built from the `points` columns, then one Feed per row, paced
+// by the recorded `realtime_ns`. Requests sent to it are discarded.
+pub struct ReplayConnection {
+  thr: Option<thread::JoinHandle<()>>,
+  running: Arc<atomic::AtomicBool>,
+  rx: mpsc::Receiver<connection::RxEvent>,
+  tx: mpsc::Sender<interface::Message>,
+}
+
+impl ReplayConnection {
+
+    pub fn new(filename: &str, speed: ReplaySpeed) -> Result<ReplayConnection, Error> {
+        if let ReplaySpeed::Scale(scale) = speed {
+            if !(scale.is_finite() && scale > 0.0) {
+                return Err(Error::Other(format!("invalid replay speed {scale}, expected a positive number")));
+            }
+        }
+        let reader = LogReader::open(filename)?;
+        if reader.channels().is_empty() {
+            return Err(Error::Other(format!("{filename} has no recorded points")));
+        }
+
+        let (recv_tx, recv_rx) = mpsc::channel();
+        let (send_tx, send_rx) = mpsc::channel();
+        let running = Arc::new(atomic::AtomicBool::new(true));
+
+        Ok(ReplayConnection {
+            thr: Some(thread::spawn({
+                let running = running.clone();
+                move || {
+                    if let Err(e) = ReplayConnection::replay_loop(reader, speed, running, &recv_tx, &send_rx) {
+                        let _ = recv_tx.send(connection::RxEvent::Error(e));
+                    }
+                }
+            })),
+            running,
+            rx: recv_rx,
+            tx: send_tx,
+        })
+    }
+
+    fn replay_loop(reader: LogReader, speed: ReplaySpeed, running: Arc<atomic::AtomicBool>,
+                   tx: &mpsc::Sender<connection::RxEvent>, requests: &mpsc::Receiver<interface::Message>) -> Result<(), Error> {
+        // Nothing answers requests, so they're dropped as they come in
+        let discard = || while requests.try_recv().is_ok() {};
+        let send = |time, payload| {
+            discard();
+            tx.send(connection::RxEvent::Message(connection::RxMessage{time, payload})).is_ok()
+        };
+
+        let mut start : Option<(SystemTime, Instant)> = None;
+        for frame in reader.frames()? {
+            if !running.load(atomic::Ordering::Relaxed) {
+              return Ok(());
+            }
+
+            let frame = frame?;
+            let (first, started) = match start {
+                Some(start) => start,
+                None => {
+                    let description = interface::Message::Description{keys: frame.keys().to_vec()};
+                    if !send(frame.time(), description) { return Ok(()); }
+                    *start.insert((frame.time(), Instant::now()))
+                },
+            };
+
+            if let ReplaySpeed::Scale(scale) = speed {
+                let offset = frame.time().duration_since(first).unwrap_or_default();
+                let due = Duration::try_from_secs_f64(offset.as_secs_f64() / scale).ok()
+                    .and_then(|delay| started.checked_add(delay))
+                    .ok_or_else(|| Error::Other(format!("replay delay out of range at speed {scale}")))?;
+                // Sleep in short steps so that dropping the connection isn't held up
+                // by a long gap in the log
+                while let Some(remaining) = due.checked_duration_since(Instant::now()) {
+                    if !running.load(atomic::Ordering::Relaxed) {
+                      return Ok(());
+                    }
+                    discard();
+                    thread::sleep(remaining.min(Duration::from_millis(100)));
+                }
+            }
+
+            let time = frame.time();
+            if !send(time, interface::Message::Feed{values: frame.into_values()}) {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl connection::Connection for ReplayConnection {
+
+    fn recv(&self, timeout: Duration) -> Result<connection::RxEvent, connection::ConnError> {
+      Ok(self.rx.recv_timeout(timeout)?)
+    }
+
+    fn get_writer(&self) -> connection::Writer {
+      connection::Writer {
+          tx: self.tx.clone(),
+      }
+    }
+}
+
+impl Drop for ReplayConnection {
+    fn drop(&mut self) {
+      self.running.store(false, atomic::Ordering::Relaxed);
+      if let Some(t) = self.thr.take() {
+          t.join().unwrap();
+      }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{Connection, ConnError, RxEvent};
+    use crate::interface::{FeedValue, Message, RequestMessage};
+    use crate::testing;
+
+    fn next_message(conn: &ReplayConnection) -> Message {
+        match conn.recv(Duration::from_secs(1)) {
+            Ok(RxEvent::Message(msg)) => msg.payload,
+            _ => panic!("expected a message"),
+        }
+    }
+
+    #[test]
+    fn replays_description_then_feed() {
+        let log = testing::write_log(&["rpm", "map"], &[
+            (0, vec![FeedValue::Int(800), FeedValue::Float(30.0)]),
+            (10, vec![FeedValue::Int(900), FeedValue::Float(35.5)]),
+        ]);
+        let conn = ReplayConnection::new(log.path(), ReplaySpeed::AsFastAsPossible).unwrap();
+        // Requests are accepted and dropped
+        conn.get_writer().send(Message::Request(RequestMessage::Ping { id: 1 })).unwrap();
+
+        assert!(matches!(next_message(&conn), Message::Description { keys } if keys == ["rpm", "map"]));
+        for expected in [[FeedValue::Int(800), FeedValue::Float(30.0)], [FeedValue::Int(900), FeedValue::Float(35.5)]] {
+            assert!(matches!(next_message(&conn), Message::Feed { values } if values == expected));
+        }
+        assert!(matches!(conn.recv(Duration::from_secs(1)), Err(ConnError::Disconnected)));
+    }
+
+    #[test]
+    fn paces_by_recorded_time() {
+        let log = testing::write_log(&["rpm"], &[
+            (0, vec![FeedValue::Int(1)]),
+            (400, vec![FeedValue::Int(2)]),
+        ]);
+        let conn = ReplayConnection::new(log.path(), ReplaySpeed::Scale(2.0)).unwrap();
+        next_message(&conn);
+        let first = next_message(&conn);
+        assert!(matches!(first, Message::Feed { .. }));
+        let started = std::time::Instant::now();
+        next_message(&conn);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_millis(600), "{elapsed:?}");
+    }
+
+    #[test]
+    fn rejects_invalid_speeds() {
+        let log = testing::write_log(&["rpm"], &[(0, vec![FeedValue::Int(1)])]);
+        for scale in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(ReplayConnection::new(log.path(), ReplaySpeed::Scale(scale)).is_err());
+        }
+    }
+}
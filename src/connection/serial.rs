@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use serialport::SerialPort;
+
+use crate::connection;
+use crate::connection::stream::StreamConnection;
+use crate::Error;
+
+// Talks to the ECU through a byte oriented tty, such as the kernel's
+// CDC-ACM driver (/dev/ttyACM*), rather than claiming the USB interface.
+pub struct SerialConnection {
+  stream: StreamConnection,
+}
+
+impl SerialConnection {
+
+    pub fn new(path: &str) -> Result<SerialConnection, Error> {
+        // CDC-ACM ignores the baud rate, but a real UART needs one
+        let port = serialport::new(path, 115200)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        SerialConnection::from_port(port)
+    }
+
+    // Uses an already opened port, e.g. one end of a `TTYPort::pair()`
+    pub fn from_port(mut port: Box<dyn SerialPort>) -> Result<SerialConnection, Error> {
+        port.set_timeout(Duration::from_millis(100))?;
+        let write_port = port.try_clone()?;
+        Ok(SerialConnection { stream: StreamConnection::new(port, write_port) })
+    }
+}
+
+impl connection::Connection for SerialConnection {
+
+    fn recv(&self, timeout: Duration) -> Result<connection::RxEvent, connection::ConnError> {
+      self.stream.recv(timeout)
+    }
+
+    fn get_writer(&self) -> connection::Writer {
+      self.stream.get_writer()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+
+    use serialport::TTYPort;
+
+    use super::*;
+    use crate::connection::{Connection, RxEvent};
+    use crate::interface::{Message, RequestMessage, ResponseValue};
+
+    const WAIT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn resyncs_and_exchanges_messages_over_a_pty() {
+        let (mut device, port) = TTYPort::pair().unwrap();
+        let conn = SerialConnection::from_port(Box::new(port)).unwrap();
+
+        let response = serde_cbor::to_vec(&Message::Response { id: 4, response: ResponseValue::Int(12) }).unwrap();
+        let keys = vec!["rpm".to_string(), "sensor.map".to_string()];
+        let description = serde_cbor::to_vec(&Message::Description { keys }).unwrap();
+        // Noise from before the device started, then each message split
+        // across writes
+        let writes = [&[0x00, 0x13, 0x42][..], &response[..3], &response[3..], &description[..20], &description[20..]];
+        for bytes in writes {
+            device.write_all(bytes).unwrap();
+            device.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        let mut received = vec![];
+        while received.len() < 2 {
+            match conn.recv(WAIT) {
+                Ok(RxEvent::Message(msg)) => received.push(msg.payload),
+                // The discarded noise
+                Ok(RxEvent::Error(_)) => (),
+                _ => panic!("expected a message"),
+            }
+        }
+        assert!(matches!(received[0], Message::Response { id: 4, response: ResponseValue::Int(12) }));
+        assert!(matches!(&received[1], Message::Description { keys } if *keys == ["rpm", "sensor.map"]));
+
+        conn.get_writer().send(Message::Request(RequestMessage::Ping { id: 9 })).unwrap();
+        device.set_timeout(WAIT).unwrap();
+        let expected = serde_cbor::to_vec(&Message::Request(RequestMessage::Ping { id: 9 })).unwrap();
+        let mut sent = vec![0; expected.len()];
+        device.read_exact(&mut sent).unwrap();
+        assert_eq!(sent, expected);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::thread;
+use std::sync::{mpsc, atomic, Arc};
+use std::time::{SystemTime, Duration, Instant};
+
+use crate::interface::{self, ResponseValue, StructurePathElement};
+use crate::connection;
+use crate::Error;
+
+#[derive(Debug, Clone)]
+pub struct SimulatedConfig {
+    pub keys: Vec<String>,
+    // Feed messages per second, at most one per nanosecond
+    pub rate: f64,
+    // Configuration tree served to Get and modified by Set. The Structure
+    // response is derived from it.
+    pub config: ResponseValue,
+}
+
+fn map(entries: Vec<(&str, ResponseValue)>) -> ResponseValue {
+    ResponseValue::Map(entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+impl Default for SimulatedConfig {
+    fn default() -> Self {
+        let keys = ["cputime", "rpm", "sensor.map", "sensor.iat", "sensor.clt",
+                    "sensor.brv", "sensor.tps", "sensor.ego", "advance", "fuel_pulsewidth_us"];
+        let outputs = (0..4)
+            .map(|i| map(vec![
+                ("pin", ResponseValue::Int(i)),
+                ("type", ResponseValue::Str(if i < 2 { "ignition" } else { "fuel" }.to_string())),
+                ("inverted", ResponseValue::Bool(false)),
+                ("angle", ResponseValue::Float((i % 2) as f32 * 360.0)),
+            ]))
+            .collect();
+        let config = map(vec![
+            ("decoder", map(vec![
+                ("type", ResponseValue::Str("tfi".to_string())),
+                ("offset", ResponseValue::Float(45.0)),
+                ("rpm_window_size", ResponseValue::Int(8)),
+            ])),
+            ("fueling", map(vec![
+                ("cylinder_cc", ResponseValue::Float(500.0)),
+                ("injector_cc", ResponseValue::Float(550.0)),
+                ("injections_per_cycle", ResponseValue::Int(2)),
+            ])),
+            ("ignition", map(vec![
+                ("dwell_us", ResponseValue::Float(2500.0)),
+                ("min_fire_time_us", ResponseValue::Float(500.0)),
+            ])),
+            ("outputs", ResponseValue::Array(outputs)),
+        ]);
+        SimulatedConfig {
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            rate: 100.0,
+            config,
+        }
+    }
+}
+
+// An in-process stand-in for an ECU. It emits the configured Description,
+// synthesizes plausible feed values and answers requests from an in-memory
+// configuration tree, so Manager consumers can run without hardware.
+pub struct SimulatedConnection {
+  thr: Option<thread::JoinHandle<()>>,
+  running: Arc<atomic::AtomicBool>,
+  rx: mpsc::Receiver<connection::RxMessage>,
+  tx: mpsc::Sender<interface::Message>,
+}
+
+fn synthesize(key: &str, index: usize, t: f64, count: u64) -> interface::FeedValue {
+    use interface::FeedValue::{Int, Float};
+    let rpm = 2500.0 + 1500.0 * (t * 0.5).sin();
+    let load = (rpm - 1000.0) / 4000.0;
+    match key {
+        "cputime" => Int((count * 1000) as u32),
+        "rpm" => Int(rpm as u32),
+        "sensor.map" => Float((30.0 + 70.0 * load) as f32),
+        "sensor.iat" => Float((25.0 + 2.0 * (t * 0.05).sin()) as f32),
+        "sensor.clt" => Float((20.0 + 70.0 * (1.0 - (-t / 120.0).exp())) as f32),
+        "sensor.brv" => Float((13.8 + 0.1 * (t * 7.0).sin()) as f32),
+        "sensor.tps" => Float((100.0 * load).clamp(0.0, 100.0) as f32),
+        "sensor.ego" => Float((1.0 + 0.05 * (t * 3.0).sin()) as f32),
+        "advance" => Float((10.0 + rpm / 250.0) as f32),
+        "fuel_pulsewidth_us" => Float((2000.0 + 4000.0 * load) as f32),
+        _ => Float((t + index as f64).sin() as f32),
+    }
+}
+
+fn structure_of(value: &ResponseValue) -> ResponseValue {
+    let leaf = |leaf_type: &str| ResponseValue::Leaf(interface::StructureLeaf {
+        leaf_type: leaf_type.to_string(),
+        description: "simulated".to_string(),
+    });
+    match value {
+        ResponseValue::Map(m) => ResponseValue::Map(
+            m.iter().map(|(k, v)| (k.clone(), structure_of(v))).collect::<HashMap<_, _>>()),
+        ResponseValue::Array(a) => ResponseValue::Array(a.iter().map(structure_of).collect()),
+        ResponseValue::Str(_) => leaf("string"),
+        ResponseValue::Float(_) => leaf("float"),
+        ResponseValue::Int(_) => leaf("uint32"),
+        ResponseValue::Bool(_) => leaf("bool"),
+        _ => leaf("unknown"),
+    }
+}
+
+fn lookup<'a>(mut node: &'a mut ResponseValue, path: &interface::StructurePath) -> Option<&'a mut ResponseValue> {
+    for element in path.elements() {
+        node = match (node, element) {
+            (ResponseValue::Map(m), StructurePathElement::MapField(f)) => m.get_mut(f)?,
+            (ResponseValue::Array(a), StructurePathElement::ArrayIndex(i)) => a.get_mut(*i as usize)?,
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+fn invalid_path() -> ResponseValue {
+    ResponseValue::Error(interface::ResponseError{ error: "invalid path".to_string() })
+}
+
+impl SimulatedConnection {
+
+    pub fn new(config: SimulatedConfig) -> Result<SimulatedConnection, Error> {
+        let period = Duration::try_from_secs_f64(1.0 / config.rate).ok()
+            .filter(|period| config.rate > 0.0 && !period.is_zero())
+            .ok_or_else(|| Error::Other(format!("invalid feed rate {}", config.rate)))?;
+
+        let (recv_tx, recv_rx) = mpsc::channel();
+        let (send_tx, send_rx) = mpsc::channel();
+        let running = Arc::new(atomic::AtomicBool::new(true));
+
+        Ok(SimulatedConnection {
+            thr: Some(thread::spawn({
+                let running = running.clone();
+                move || SimulatedConnection::sim_loop(config, period, running, recv_tx, send_rx)
+            })),
+            running,
+            rx: recv_rx,
+            tx: send_tx,
+        })
+    }
+
+    fn respond(config: &mut ResponseValue, request: interface::RequestMessage) -> interface::Message {
+        let id = request.id();
+        let response = match request {
+            interface::RequestMessage::Ping{..} => ResponseValue::Str("pong".to_string()),
+            interface::RequestMessage::Structure{..} => structure_of(config),
+            interface::RequestMessage::Get{path, ..} => match lookup(config, &path) {
+                Some(value) => value.clone(),
+                None => invalid_path(),
+            },
+            interface::RequestMessage::Set{path, value, ..} => match lookup(config, &path) {
+                Some(ResponseValue::Map(_) | ResponseValue::Array(_)) =>
+                    ResponseValue::Error(interface::ResponseError{ error: "not a leaf".to_string() }),
+                Some(current) => {
+                    *current = match (&*current, value) {
+                        (ResponseValue::Float(_), ResponseValue::Int(x)) => ResponseValue::Float(x as f32),
+                        (_, value) => value,
+                    };
+                    current.clone()
+                },
+                None => invalid_path(),
+            },
+        };
+        interface::Message::Response{ id, response }
+    }
+
+    fn sim_loop(mut config: SimulatedConfig, period: Duration, running: Arc<atomic::AtomicBool>,
+                tx: mpsc::Sender<connection::RxMessage>, rx: mpsc::Receiver<interface::Message>) {
+        let started = Instant::now();
+        let mut next_feed = started;
+        let mut next_description = started;
+        let mut count : u64 = 0;
+
+        let send = |payload| tx.send(connection::RxMessage{ time: SystemTime::now(), payload }).is_ok();
+
+        loop {
+            if !running.load(atomic::Ordering::Relaxed) {
+              break;
+            }
+
+            let now = Instant::now();
+            if now >= next_description {
+                // The firmware repeats its description so late joiners can decode the feed
+                if !send(interface::Message::Description{keys: config.keys.clone()}) { break; }
+                next_description += Duration::from_secs(1);
+            }
+            if now >= next_feed {
+                let t = (now - started).as_secs_f64();
+                let values = config.keys.iter().enumerate()
+                    .map(|(i, k)| synthesize(k, i, t, count))
+                    .collect();
+                if !send(interface::Message::Feed{values}) { break; }
+                count += 1;
+                next_feed += period;
+            }
+
+            let wait = next_feed.min(next_description).saturating_duration_since(Instant::now());
+            if let Ok(interface::Message::Request(request)) = rx.recv_timeout(wait.min(Duration::from_millis(100))) {
+                let response = SimulatedConnection::respond(&mut config.config, request);
+                if !send(response) { break; }
+            }
+        }
+    }
+}
+
+impl connection::Connection for SimulatedConnection {
+
+    fn recv(&self, timeout: Duration) -> Result<connection::RxEvent, connection::ConnError> {
+      Ok(connection::RxEvent::Message(self.rx.recv_timeout(timeout)?))
+    }
+
+    fn get_writer(&self) -> connection::Writer {
+      connection::Writer {
+          tx: self.tx.clone(),
+      }
+    }
+}
+
+impl Drop for SimulatedConnection {
+    fn drop(&mut self) {
+      self.running.store(false, atomic::Ordering::Relaxed);
+      if let Some(t) = self.thr.take() {
+          t.join().unwrap();
+      }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Manager, SubscribeOptions};
+    use crate::interface::{FeedValue, RequestMessage};
+
+    const WAIT: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn answers_requests_through_a_manager() {
+        let g = Manager::new(Box::new(SimulatedConnection::new(SimulatedConfig::default()).unwrap()));
+        let get = |path: &str| RequestMessage::Get { id: 0, path: path.parse().unwrap() };
+
+        assert_eq!(g.request_blocking(RequestMessage::Ping { id: 0 }, WAIT).unwrap(),
+                   ResponseValue::Str("pong".to_string()));
+        assert_eq!(g.request_blocking(get("decoder.offset"), WAIT).unwrap(), ResponseValue::Float(45.0));
+
+        let set = RequestMessage::Set { id: 0, path: "outputs[1].angle".parse().unwrap(), value: ResponseValue::Int(90) };
+        assert_eq!(g.request_blocking(set, WAIT).unwrap(), ResponseValue::Float(90.0));
+        assert_eq!(g.request_blocking(get("outputs[1].angle"), WAIT).unwrap(), ResponseValue::Float(90.0));
+        assert!(g.request_blocking(get("outputs[9]"), WAIT).is_err());
+    }
+
+    #[test]
+    fn feeds_the_configured_keys() {
+        let config = SimulatedConfig { keys: vec!["rpm".to_string(), "other".to_string()], ..Default::default() };
+        let g = Manager::new(Box::new(SimulatedConnection::new(config).unwrap()));
+        let feed = g.subscribe(SubscribeOptions::default());
+
+        let first = feed.recv_timeout(WAIT).unwrap();
+        assert_eq!(first.keys(), ["rpm", "other"]);
+        assert!(matches!(first.get("rpm"), Some(FeedValue::Int(_))));
+        let second = feed.recv_timeout(WAIT).unwrap();
+        assert!(second.time() > first.time());
+    }
+
+    #[test]
+    fn rejects_invalid_rates() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e12] {
+            let config = SimulatedConfig { rate, ..Default::default() };
+            assert!(SimulatedConnection::new(config).is_err(), "{rate}");
+        }
+    }
+}
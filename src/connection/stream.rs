@@ -0,0 +1,360 @@
+use std::io::{Read, Write};
+use std::thread;
+use std::sync::{mpsc, atomic, Arc};
+use std::time::{SystemTime, Duration};
+
+use serde::Deserialize;
+
+use crate::interface;
+use crate::connection;
+use crate::Error;
+
+// A partial item larger than this is assumed to be garbage that happened to
+// look like the start of a long CBOR value. Matches the largest message the
+// packet based transports accept.
+const MAX_PENDING: usize = 16384;
+
+// Every message is a CBOR map, so anything else can't start one
+fn is_map_start(byte: u8) -> bool {
+    byte >> 5 == 5
+}
+
+enum Scan {
+    // The item is this many bytes long
+    Complete(usize),
+    Incomplete,
+    // Not CBOR, or declares more than MAX_PENDING bytes
+    Invalid,
+}
+
+// Walks the item headers at the start of `buf` without decoding anything, so
+// a corrupt length is rejected as soon as its header arrives rather than
+// after waiting for MAX_PENDING bytes
+fn scan(buf: &[u8]) -> Scan {
+    // Items left in each open array or map, None for indefinite length ones
+    // that run until a break byte
+    let mut open: Vec<Option<usize>> = vec![Some(1)];
+    let mut pos = 0;
+    loop {
+        match open.last_mut() {
+            // A string at the end of the item can run past what's arrived
+            None if pos > buf.len() => return Scan::Incomplete,
+            None => return Scan::Complete(pos),
+            Some(Some(0)) => {
+                open.pop();
+                continue;
+            },
+            Some(Some(n)) => *n -= 1,
+            Some(None) => (),
+        }
+        let Some(&initial) = buf.get(pos) else { return Scan::Incomplete };
+        pos += 1;
+        if initial == 0xff {
+            if open.last() != Some(&None) {
+                return Scan::Invalid;
+            }
+            open.pop();
+            continue;
+        }
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let arg = match info {
+            0..=23 => Some(info as u64),
+            24..=27 => {
+                let size = 1 << (info - 24);
+                let Some(bytes) = buf.get(pos..pos + size) else { return Scan::Incomplete };
+                pos += size;
+                Some(bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64))
+            },
+            31 if (2..=5).contains(&major) => None,
+            _ => return Scan::Invalid,
+        };
+        // Every item takes at least a byte, so no length can exceed what's
+        // left of MAX_PENDING
+        let limit = MAX_PENDING.saturating_sub(pos) as u64;
+        match (major, arg) {
+            (0 | 1 | 7, _) => (),
+            (6, _) => open.push(Some(1)),
+            (2..=5, Some(len)) if len > limit => return Scan::Invalid,
+            (2 | 3, Some(len)) => pos += len as usize,
+            (4, Some(len)) => open.push(Some(len as usize)),
+            (5, Some(len)) if 2 * len > limit => return Scan::Invalid,
+            (5, Some(len)) => open.push(Some(2 * len as usize)),
+            // Indefinite length, strings are a series of chunks
+            _ => open.push(None),
+        }
+        if pos > MAX_PENDING {
+            return Scan::Invalid;
+        }
+    }
+}
+
+// Splits a byte stream into CBOR encoded messages. Transports without
+// message boundaries (serial, TCP) push whatever they read and pull out
+// complete messages. Bytes that don't start a message are discarded until the
+// stream lines up again.
+pub(crate) struct StreamDecoder {
+    buf: Vec<u8>,
+    discarded: usize,
+}
+
+impl StreamDecoder {
+    pub fn new() -> StreamDecoder {
+        StreamDecoder { buf: Vec::new(), discarded: 0 }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn next_message(&mut self) -> Option<interface::Message> {
+        // Skipped bytes are only removed from the buffer once at the end
+        let mut start = 0;
+        let mut found = None;
+        while start < self.buf.len() {
+            let rest = &self.buf[start..];
+            if !is_map_start(rest[0]) {
+                start += rest.iter().position(|&b| is_map_start(b)).unwrap_or(rest.len());
+                continue;
+            }
+            let len = match scan(rest) {
+                Scan::Complete(len) => len,
+                Scan::Incomplete => break,
+                Scan::Invalid => {
+                    start += 1;
+                    continue;
+                },
+            };
+            let msg = serde_cbor::Value::deserialize(&mut serde_cbor::Deserializer::from_slice(&rest[..len]))
+                .ok()
+                .and_then(|value| serde_cbor::value::from_value(value).ok());
+            match msg {
+                Some(msg) => {
+                    found = Some((msg, len));
+                    break;
+                },
+                // Well formed CBOR, but not a message
+                None => start += 1,
+            }
+        }
+        self.discarded += start;
+        match found {
+            Some((msg, len)) => {
+                self.buf.drain(..start + len);
+                Some(msg)
+            },
+            None => {
+                self.buf.drain(..start);
+                None
+            },
+        }
+    }
+
+    // Number of bytes thrown away since the last call
+    pub fn take_discarded(&mut self) -> usize {
+        std::mem::take(&mut self.discarded)
+    }
+}
+
+// The threads behind a connection over any byte stream. Transports open
+// their device and hand over a reader and a writer, which need a read timeout
+// so the threads notice when the connection is dropped.
+pub(crate) struct StreamConnection {
+    recv_thr: Option<thread::JoinHandle<()>>,
+    write_thr: Option<thread::JoinHandle<()>>,
+    running: Arc<atomic::AtomicBool>,
+    rx: mpsc::Receiver<connection::RxEvent>,
+    tx: mpsc::Sender<interface::Message>,
+}
+
+impl StreamConnection {
+
+    pub fn new<R, W>(reader: R, writer: W) -> StreamConnection
+    where R: Read + Send + 'static, W: Write + Send + 'static {
+        let (recv_tx, recv_rx) = mpsc::channel();
+        let (send_tx, send_rx) = mpsc::channel();
+
+        let running = Arc::new(atomic::AtomicBool::new(true));
+
+        StreamConnection {
+            recv_thr: Some(thread::spawn({
+                let running = running.clone();
+                let recv_tx = recv_tx.clone();
+                move || StreamConnection::recv_loop(reader, running, recv_tx)
+            })),
+            write_thr: Some(thread::spawn({
+                let running = running.clone();
+                move || StreamConnection::send_loop(writer, running, send_rx, recv_tx)
+            })),
+            running,
+            rx: recv_rx,
+            tx: send_tx,
+        }
+    }
+
+    fn send_loop(mut writer: impl Write, running: Arc<atomic::AtomicBool>,
+                 rx: mpsc::Receiver<interface::Message>, errors: mpsc::Sender<connection::RxEvent>) {
+        loop {
+            if !running.load(atomic::Ordering::Relaxed) {
+              break;
+            }
+
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(msg) => {
+                    let sent = serde_cbor::to_vec(&msg)
+                        .map_err(Error::from)
+                        .and_then(|bytes| Ok(writer.write_all(&bytes[..])?));
+                    if let Err(e) = sent {
+                        let _ = errors.send(connection::RxEvent::Error(e));
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                _ => break,
+            }
+        }
+    }
+
+    fn recv_loop(mut reader: impl Read, running: Arc<atomic::AtomicBool>, tx: mpsc::Sender<connection::RxEvent>) {
+        let mut decoder = StreamDecoder::new();
+        let mut recvbuf = [0; MAX_PENDING];
+        loop {
+          if !running.load(atomic::Ordering::Relaxed) {
+            break;
+          }
+
+          match reader.read(&mut recvbuf) {
+            // Remote end closed the socket, or the tty hung up
+            Ok(0) => {
+              let _ = tx.send(connection::RxEvent::Link(connection::LinkStatus::Disconnected));
+              break;
+            },
+            Ok(n_bytes) => {
+              decoder.push(&recvbuf[0..n_bytes]);
+              while let Some(payload) = decoder.next_message() {
+                if tx.send(connection::RxEvent::Message(connection::RxMessage{
+                    time: SystemTime::now(),
+                    payload,
+                })).is_err() { return; }
+              }
+              let discarded = decoder.take_discarded();
+              if discarded > 0 {
+                let reason = format!("discarded {discarded} bytes between messages");
+                if tx.send(connection::RxEvent::Error(Error::Decode(reason))).is_err() { return; }
+              }
+            },
+            Err(e) => match e.kind() {
+              std::io::ErrorKind::TimedOut => (),
+              std::io::ErrorKind::WouldBlock => (),
+              std::io::ErrorKind::Interrupted => (),
+              _ => {
+                let _ = tx.send(connection::RxEvent::Error(e.into()));
+                let _ = tx.send(connection::RxEvent::Link(connection::LinkStatus::Disconnected));
+                break;
+              },
+            },
+          }
+        }
+    }
+}
+
+impl connection::Connection for StreamConnection {
+
+    fn recv(&self, timeout: Duration) -> Result<connection::RxEvent, connection::ConnError> {
+      Ok(self.rx.recv_timeout(timeout)?)
+    }
+
+    fn get_writer(&self) -> connection::Writer {
+      connection::Writer {
+          tx: self.tx.clone(),
+      }
+    }
+}
+
+impl Drop for StreamConnection {
+    fn drop(&mut self) {
+      self.running.store(false, atomic::Ordering::Relaxed);
+      if let Some(t) = self.recv_thr.take() {
+          t.join().unwrap();
+      }
+      if let Some(t) = self.write_thr.take() {
+          t.join().unwrap();
+      }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interface::{Message, ResponseValue};
+
+    fn response(id: u32) -> Vec<u8> {
+        serde_cbor::to_vec(&Message::Response { id, response: ResponseValue::Int(id) }).unwrap()
+    }
+
+    fn description() -> Vec<u8> {
+        let keys = vec!["rpm".to_string(), "sensor.map".to_string()];
+        serde_cbor::to_vec(&Message::Description { keys }).unwrap()
+    }
+
+    fn response_id(msg: Option<Message>) -> Option<u32> {
+        match msg {
+            Some(Message::Response { id, .. }) => Some(id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn frames_split_at_any_point() {
+        let bytes = response(7);
+        for split in 1..bytes.len() {
+            let mut decoder = StreamDecoder::new();
+            decoder.push(&bytes[..split]);
+            assert!(decoder.next_message().is_none());
+            decoder.push(&bytes[split..]);
+            assert_eq!(response_id(decoder.next_message()), Some(7));
+            assert_eq!(decoder.take_discarded(), 0);
+        }
+        // Ends in a string, so most splits land inside it
+        let bytes = description();
+        for split in 1..bytes.len() {
+            let mut decoder = StreamDecoder::new();
+            decoder.push(&bytes[..split]);
+            assert!(decoder.next_message().is_none(), "split at {split}");
+            decoder.push(&bytes[split..]);
+            assert!(matches!(decoder.next_message(), Some(Message::Description { keys }) if keys == ["rpm", "sensor.map"]));
+            assert_eq!(decoder.take_discarded(), 0);
+        }
+    }
+
+    #[test]
+    fn garbage_between_frames_is_discarded() {
+        let mut decoder = StreamDecoder::new();
+        decoder.push(&response(1));
+        // 0xa0 is an empty map, valid CBOR but not a message
+        decoder.push(&[0x00, 0x13, 0xa0, 0x42]);
+        decoder.push(&response(2));
+        assert_eq!(response_id(decoder.next_message()), Some(1));
+        assert_eq!(response_id(decoder.next_message()), Some(2));
+        assert!(decoder.next_message().is_none());
+        assert_eq!(decoder.take_discarded(), 4);
+    }
+
+    #[test]
+    fn oversize_header_is_dropped_without_waiting() {
+        let mut decoder = StreamDecoder::new();
+        // A map claiming 2^32 entries, then a text string claiming 1MiB
+        decoder.push(&[0xba, 0xff, 0xff, 0xff, 0xff]);
+        decoder.push(&[0xa1, 0x7a, 0x00, 0x10, 0x00, 0x00]);
+        decoder.push(&response(3));
+        assert_eq!(response_id(decoder.next_message()), Some(3));
+        assert_eq!(decoder.take_discarded(), 11);
+    }
+
+    #[test]
+    fn pending_frame_waits_for_the_rest() {
+        let mut decoder = StreamDecoder::new();
+        // A 1000 byte string fits in MAX_PENDING, so it's worth waiting for
+        decoder.push(&[0xa1, 0x79, 0x03, 0xe8]);
+        assert!(decoder.next_message().is_none());
+        assert_eq!(decoder.take_discarded(), 0);
+    }
+}
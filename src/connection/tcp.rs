@@ -0,0 +1,70 @@
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::connection;
+use crate::connection::stream::StreamConnection;
+use crate::Error;
+
+// Reads a continuous CBOR stream from a TCP socket, e.g. a bridge that
+// exposes the ECU's serial or USB link over the network.
+pub struct TcpConnection {
+  stream: StreamConnection,
+}
+
+impl TcpConnection {
+
+    pub fn new(addr: &str) -> Result<TcpConnection, Error> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let recv_stream = stream.try_clone()?;
+        Ok(TcpConnection { stream: StreamConnection::new(recv_stream, stream) })
+    }
+}
+
+impl connection::Connection for TcpConnection {
+
+    fn recv(&self, timeout: Duration) -> Result<connection::RxEvent, connection::ConnError> {
+      self.stream.recv(timeout)
+    }
+
+    fn get_writer(&self) -> connection::Writer {
+      self.stream.get_writer()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::connection::{Connection, LinkStatus, RxEvent};
+    use crate::interface::{Message, RequestMessage, ResponseValue};
+
+    #[test]
+    fn exchanges_messages_until_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpConnection::new(&listener.local_addr().unwrap().to_string()).unwrap();
+        let (mut device, _) = listener.accept().unwrap();
+
+        let response = Message::Response { id: 4, response: ResponseValue::Int(12) };
+        device.write_all(&serde_cbor::to_vec(&response).unwrap()).unwrap();
+        match conn.recv(Duration::from_secs(1)) {
+            Ok(RxEvent::Message(msg)) => assert!(matches!(msg.payload, Message::Response { id: 4, .. })),
+            _ => panic!("expected the response"),
+        }
+
+        conn.get_writer().send(Message::Request(RequestMessage::Ping { id: 9 })).unwrap();
+        device.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let sent: Message = serde_cbor::Deserializer::from_reader(&device)
+            .into_iter().next().unwrap().unwrap();
+        assert!(matches!(sent, Message::Request(RequestMessage::Ping { id: 9 })));
+
+        drop(device);
+        match conn.recv(Duration::from_secs(1)) {
+            Ok(RxEvent::Link(status)) => assert_eq!(status, LinkStatus::Disconnected),
+            _ => panic!("expected a disconnect"),
+        }
+    }
+}
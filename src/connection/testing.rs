@@ -0,0 +1,56 @@
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use crate::interface;
+use crate::connection::{Connection, ConnError, RxEvent, RxMessage, Writer};
+
+// A connection driven by the test through the matching `TestDevice`
+pub(crate) struct TestConnection {
+    rx: mpsc::Receiver<RxEvent>,
+    tx: mpsc::Sender<interface::Message>,
+}
+
+pub(crate) struct TestDevice {
+    events: mpsc::Sender<RxEvent>,
+    requests: mpsc::Receiver<interface::Message>,
+}
+
+pub(crate) fn pair() -> (TestConnection, TestDevice) {
+    let (events, rx) = mpsc::channel();
+    let (tx, requests) = mpsc::channel();
+    (TestConnection { rx, tx }, TestDevice { events, requests })
+}
+
+impl TestDevice {
+    pub fn send_event(&self, event: RxEvent) {
+        let _ = self.events.send(event);
+    }
+
+    pub fn send(&self, payload: interface::Message) {
+        let _ = self.events.send(RxEvent::Message(RxMessage { time: SystemTime::now(), payload }));
+    }
+
+    pub fn respond(&self, id: u32, response: interface::ResponseValue) {
+        self.send(interface::Message::Response { id, response });
+    }
+
+    // The next request the Manager sent, None if there wasn't one in time
+    pub fn request(&self, timeout: Duration) -> Option<interface::RequestMessage> {
+        loop {
+            match self.requests.recv_timeout(timeout).ok()? {
+                interface::Message::Request(request) => return Some(request),
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Connection for TestConnection {
+    fn recv(&self, timeout: Duration) -> Result<RxEvent, ConnError> {
+        Ok(self.rx.recv_timeout(timeout)?)
+    }
+
+    fn get_writer(&self) -> Writer {
+        Writer { tx: self.tx.clone() }
+    }
+}
@@ -5,45 +5,49 @@ use std::time::{SystemTime, Duration};
 
 use crate::interface;
 use crate::connection;
+use crate::Error;
 
 pub struct UdpConnection {
   recv_thr: Option<thread::JoinHandle<()>>,
   write_thr: Option<thread::JoinHandle<()>>,
   running: Arc<atomic::AtomicBool>,
-  rx: mpsc::Receiver<connection::RxMessage>,
+  rx: mpsc::Receiver<connection::RxEvent>,
   tx: mpsc::Sender<interface::Message>,
 
 }
 
 impl UdpConnection {
 
-    pub fn new(local_addr: &str, remote_addr: &str) -> UdpConnection {
-        let socket = UdpSocket::bind(local_addr).expect("socket");
+    pub fn new(local_addr: &str, remote_addr: &str) -> Result<UdpConnection, Error> {
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let recv_socket = socket.try_clone()?;
 
         let (recv_tx, recv_rx) = mpsc::channel();
         let (send_tx, send_rx) = mpsc::channel();
 
         let running = Arc::new(atomic::AtomicBool::new(true));
 
-        UdpConnection {
+        Ok(UdpConnection {
             recv_thr: Some(thread::spawn({
-                let socket = socket.try_clone().unwrap();
                 let running = running.clone();
-                move || UdpConnection::recv_loop(socket, running, recv_tx)
+                let recv_tx = recv_tx.clone();
+                move || UdpConnection::recv_loop(recv_socket, running, recv_tx)
             })),
             write_thr: Some(thread::spawn({
                 let socket = socket;
                 let running = running.clone();
                 let remote_addr = remote_addr.to_owned();
-                move || UdpConnection::send_loop(socket, running, remote_addr, send_rx)
+                move || UdpConnection::send_loop(socket, running, remote_addr, send_rx, recv_tx)
             })),
             running,
             rx: recv_rx,
             tx: send_tx,
-        }
+        })
     }
 
-    fn send_loop(socket: UdpSocket, running: Arc<atomic::AtomicBool>, addr: String, rx: mpsc::Receiver<interface::Message>) {
+    fn send_loop(socket: UdpSocket, running: Arc<atomic::AtomicBool>, addr: String,
+                 rx: mpsc::Receiver<interface::Message>, errors: mpsc::Sender<connection::RxEvent>) {
         loop {
             if !running.load(atomic::Ordering::Relaxed) {
               break;
@@ -51,8 +55,12 @@ impl UdpConnection {
 
             match rx.recv_timeout(Duration::from_millis(100)) {
                 Ok(msg) => {
-                    let bytes = serde_cbor::to_vec(&msg).unwrap();
-                    socket.send_to(&bytes[..], &addr).unwrap();
+                    let sent = serde_cbor::to_vec(&msg)
+                        .map_err(Error::from)
+                        .and_then(|bytes| Ok(socket.send_to(&bytes[..], &addr)?));
+                    if let Err(e) = sent {
+                        let _ = errors.send(connection::RxEvent::Error(e));
+                    }
                 },
                 Err(mpsc::RecvTimeoutError::Timeout) => (),
                 _ => break,
@@ -60,8 +68,7 @@ impl UdpConnection {
         }
     }
 
-    fn recv_loop(socket: UdpSocket, running: Arc<atomic::AtomicBool>, tx: mpsc::Sender<connection::RxMessage>) {
-        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    fn recv_loop(socket: UdpSocket, running: Arc<atomic::AtomicBool>, tx: mpsc::Sender<connection::RxEvent>) {
         let mut recvbuf = [0; 16384];
         loop {
           if !running.load(atomic::Ordering::Relaxed) {
@@ -69,30 +76,34 @@ impl UdpConnection {
           }
 
           let recvd = socket.recv_from(&mut recvbuf);
-          match recvd {
+          let event = match recvd {
             Ok((n_bytes, _)) => {
-              let n = serde_cbor::de::from_slice(&recvbuf[0..n_bytes]).unwrap();
-              if tx.send(connection::RxMessage{
-                  time: SystemTime::now(),
-                  payload: n,
-              }).is_err() { break; }
+              match serde_cbor::de::from_slice(&recvbuf[0..n_bytes]) {
+                Ok(payload) => connection::RxEvent::Message(connection::RxMessage{
+                    time: SystemTime::now(),
+                    payload,
+                }),
+                Err(e) => connection::RxEvent::Error(Error::Decode(e.to_string())),
+              }
             },
             Err(e) => match e.kind() {
-              std::io::ErrorKind::TimedOut => (),
-              std::io::ErrorKind::WouldBlock => (),
-              x => println!("{}, {}", e, x),
+              std::io::ErrorKind::TimedOut => continue,
+              std::io::ErrorKind::WouldBlock => continue,
+              _ => connection::RxEvent::Error(e.into()),
             },
-        }
+          };
+          if tx.send(event).is_err() { break; }
       }
     }
 }
 
 impl connection::Connection for UdpConnection {
 
-    fn recv(&self, timeout: Duration) -> Result<connection::RxMessage, connection::ConnError> {
+    fn recv(&self, timeout: Duration) -> Result<connection::RxEvent, connection::ConnError> {
       Ok(self.rx.recv_timeout(timeout)?)
     }
 
+    #[allow(clippy::needless_return)]
     fn get_writer(&self) -> connection::Writer {
       return connection::Writer { 
           tx: self.tx.clone(),
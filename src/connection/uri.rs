@@ -0,0 +1,183 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::connection::usb::{DEFAULT_PRODUCT_ID, DEFAULT_VENDOR_ID};
+use crate::connection::{
+    Connection, ReplayConnection, ReplaySpeed, SerialConnection, SimulatedConfig,
+    SimulatedConnection, TcpConnection, UdpConnection, UsbConnection, UsbConnectionBuilder,
+};
+use crate::Error;
+
+// Names a transport and where to find the device on it:
+//
+//   usb                      first device with the default VID/PID
+//   usb:0483:5740            first device with this VID/PID
+//   usb:0483:5740:SERIAL     the device with this serial number
+//   udp://local->remote      e.g. udp://127.0.0.1:5556->127.0.0.1:5555
+//   tcp://host:port
+//   serial:/dev/ttyACM0
+//   replay:log.sq3           a recorded log, played back in real time
+//   sim                      a simulated ECU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionUri {
+    Usb { vendor_id: u16, product_id: u16, serial: Option<String> },
+    Udp { local: String, remote: String },
+    Tcp { addr: String },
+    Serial { path: String },
+    Replay { path: String },
+    Simulated,
+}
+
+impl ConnectionUri {
+    // Selects the device for a USB connection, None for other transports
+    pub fn usb_builder(&self) -> Option<UsbConnectionBuilder> {
+        let ConnectionUri::Usb { vendor_id, product_id, serial } = self else { return None };
+        let mut builder = UsbConnection::builder().vid_pid(*vendor_id, *product_id);
+        if let Some(serial) = serial {
+            builder = builder.serial(serial);
+        }
+        Some(builder)
+    }
+
+    pub fn open(&self) -> Result<Box<dyn Connection + Send>, Error> {
+        Ok(match self {
+            ConnectionUri::Usb { .. } => Box::new(self.usb_builder().unwrap_or_default().build()?),
+            ConnectionUri::Udp { local, remote } => Box::new(UdpConnection::new(local, remote)?),
+            ConnectionUri::Tcp { addr } => Box::new(TcpConnection::new(addr)?),
+            ConnectionUri::Serial { path } => Box::new(SerialConnection::new(path)?),
+            ConnectionUri::Replay { path } => Box::new(ReplayConnection::new(path, ReplaySpeed::Scale(1.0))?),
+            ConnectionUri::Simulated => Box::new(SimulatedConnection::new(SimulatedConfig::default())?),
+        })
+    }
+}
+
+fn parse_id(id: &str, uri: &str) -> Result<u16, Error> {
+    u16::from_str_radix(id, 16)
+        .map_err(|_| Error::Other(format!("invalid USB id {id} in {uri}, expected 4 hex digits")))
+}
+
+impl FromStr for ConnectionUri {
+    type Err = Error;
+
+    fn from_str(uri: &str) -> Result<ConnectionUri, Error> {
+        let missing = |what: &str| Error::Other(format!("{uri} is missing the {what}"));
+        if uri == "usb" {
+            return Ok(ConnectionUri::Usb {
+                vendor_id: DEFAULT_VENDOR_ID,
+                product_id: DEFAULT_PRODUCT_ID,
+                serial: None,
+            });
+        }
+        if uri == "sim" {
+            return Ok(ConnectionUri::Simulated);
+        }
+        if let Some(rest) = uri.strip_prefix("usb:") {
+            let mut parts = rest.splitn(3, ':');
+            let vendor_id = parse_id(parts.next().unwrap_or_default(), uri)?;
+            let product_id = parse_id(parts.next().ok_or_else(|| missing("product id"))?, uri)?;
+            let serial = parts.next().filter(|s| !s.is_empty()).map(str::to_string);
+            return Ok(ConnectionUri::Usb { vendor_id, product_id, serial });
+        }
+        if let Some(rest) = uri.strip_prefix("udp://") {
+            let (local, remote) = rest.split_once("->").ok_or_else(|| missing("->remote address"))?;
+            if local.is_empty() || remote.is_empty() {
+                return Err(missing("local or remote address"));
+            }
+            return Ok(ConnectionUri::Udp { local: local.to_string(), remote: remote.to_string() });
+        }
+        if let Some(addr) = uri.strip_prefix("tcp://") {
+            if addr.is_empty() {
+                return Err(missing("address"));
+            }
+            return Ok(ConnectionUri::Tcp { addr: addr.to_string() });
+        }
+        if let Some(path) = uri.strip_prefix("serial:") {
+            if path.is_empty() {
+                return Err(missing("port"));
+            }
+            return Ok(ConnectionUri::Serial { path: path.to_string() });
+        }
+        if let Some(path) = uri.strip_prefix("replay:") {
+            if path.is_empty() {
+                return Err(missing("log file"));
+            }
+            return Ok(ConnectionUri::Replay { path: path.to_string() });
+        }
+        Err(Error::Other(format!(
+            "unknown connection {uri}, expected usb, udp://, tcp://, serial:, replay: or sim")))
+    }
+}
+
+// Round trips through `FromStr`, so it can be stored and reused
+impl fmt::Display for ConnectionUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionUri::Usb { vendor_id: DEFAULT_VENDOR_ID, product_id: DEFAULT_PRODUCT_ID, serial: None } =>
+                write!(f, "usb"),
+            ConnectionUri::Usb { vendor_id, product_id, serial } => {
+                write!(f, "usb:{vendor_id:04x}:{product_id:04x}")?;
+                match serial {
+                    Some(serial) => write!(f, ":{serial}"),
+                    None => Ok(()),
+                }
+            },
+            ConnectionUri::Udp { local, remote } => write!(f, "udp://{local}->{remote}"),
+            ConnectionUri::Tcp { addr } => write!(f, "tcp://{addr}"),
+            ConnectionUri::Serial { path } => write!(f, "serial:{path}"),
+            ConnectionUri::Replay { path } => write!(f, "replay:{path}"),
+            ConnectionUri::Simulated => write!(f, "sim"),
+        }
+    }
+}
+
+// Builds the connection described by `uri`, see `ConnectionUri`
+pub fn open(uri: &str) -> Result<Box<dyn Connection + Send>, Error> {
+    uri.parse::<ConnectionUri>()?.open()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_each_transport() {
+        let parse = |uri: &str| uri.parse::<ConnectionUri>().unwrap();
+        assert_eq!(parse("usb"), ConnectionUri::Usb {
+            vendor_id: DEFAULT_VENDOR_ID, product_id: DEFAULT_PRODUCT_ID, serial: None });
+        assert_eq!(parse("usb:1209:ABCD:0042"), ConnectionUri::Usb {
+            vendor_id: 0x1209, product_id: 0xabcd, serial: Some("0042".to_string()) });
+        assert_eq!(parse("usb:1209:abcd:"), ConnectionUri::Usb {
+            vendor_id: 0x1209, product_id: 0xabcd, serial: None });
+        assert_eq!(parse("udp://0.0.0.0:5556->10.0.0.2:5555"), ConnectionUri::Udp {
+            local: "0.0.0.0:5556".to_string(), remote: "10.0.0.2:5555".to_string() });
+        assert_eq!(parse("tcp://bridge:7000"), ConnectionUri::Tcp { addr: "bridge:7000".to_string() });
+        assert_eq!(parse("serial:/dev/ttyACM0"), ConnectionUri::Serial { path: "/dev/ttyACM0".to_string() });
+        assert_eq!(parse("replay:logs/run.sq3"), ConnectionUri::Replay { path: "logs/run.sq3".to_string() });
+        assert_eq!(parse("sim"), ConnectionUri::Simulated);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for uri in ["usb", "usb:1209:abcd", "usb:1209:abcd:0042", "udp://0.0.0.0:5556->10.0.0.2:5555",
+                    "tcp://bridge:7000", "serial:/dev/ttyACM0", "replay:run.sq3", "sim"] {
+            assert_eq!(uri.parse::<ConnectionUri>().unwrap().to_string(), uri);
+        }
+        // The default ids are written the short way
+        assert_eq!("usb:0483:5740".parse::<ConnectionUri>().unwrap().to_string(), "usb");
+    }
+
+    #[test]
+    fn rejects_malformed_uris() {
+        for uri in ["", "usb:", "usb:1209", "usb:xyz:abcd", "usb:12345:abcd", "udp://a", "udp://->b",
+                    "tcp://", "serial:", "replay:", "bluetooth:x"] {
+            assert!(uri.parse::<ConnectionUri>().is_err(), "{uri}");
+        }
+    }
+
+    #[test]
+    fn opens_local_transports() {
+        let sim = open("sim").unwrap();
+        assert!(matches!(sim.recv(std::time::Duration::from_secs(1)), Ok(crate::connection::RxEvent::Message(_))));
+        assert!(open("replay:/nonexistent/log.sq3").is_err());
+    }
+}
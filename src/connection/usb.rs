@@ -1,116 +1,439 @@
-use std::sync::{mpsc, atomic, Arc};
-use std::time::{SystemTime, Duration};
+use std::sync::{mpsc, atomic, Arc, Mutex};
+use std::time::{SystemTime, Duration, Instant};
 use crate::interface;
-use crate::connection::{Connection, ConnError, RxMessage, Writer};
-use rusb::{Context, UsbContext, HotplugBuilder, Device};
+use crate::Error;
+use crate::connection::{Connection, ConnError, LinkStatus, RxEvent, RxMessage, Writer};
+use rusb::{Context, DeviceHandle, UsbContext, HotplugBuilder, Device};
 use rusb_async::TransferPool;
 
+pub(crate) const DEFAULT_VENDOR_ID: u16 = 0x0483;
+pub(crate) const DEFAULT_PRODUCT_ID: u16 = 0x5740;
+
+// How often to look for the device when hotplug notification is unavailable
+// (or missed an arrival)
+const RESCAN_INTERVAL: Duration = Duration::from_millis(500);
+
+type SharedHandle = Arc<Mutex<Option<Arc<DeviceHandle<Context>>>>>;
+
+// The device a UsbConnection has open, which can change whenever it
+// reconnects. Cloned handles follow the same connection.
+#[derive(Debug, Clone, Default)]
+pub struct OpenedDevice(Arc<Mutex<Option<UsbDeviceInfo>>>);
+
+impl OpenedDevice {
+    // None while the device is away
+    pub fn get(&self) -> Option<UsbDeviceInfo> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, info: Option<UsbDeviceInfo>) {
+        *self.0.lock().unwrap() = info;
+    }
+}
+
+// Waits for the ECU to appear, and reopens it after it is unplugged or
+// resets. Link changes are reported as `RxEvent::Link`.
 pub struct UsbConnection {
-    recv_rx: mpsc::Receiver<RxMessage>,
+    recv_rx: mpsc::Receiver<RxEvent>,
     send_tx: mpsc::Sender<interface::Message>,
     running: Arc<atomic::AtomicBool>,
+    opened: OpenedDevice,
     recv_thread: Option<std::thread::JoinHandle<()>>,
     send_thread: Option<std::thread::JoinHandle<()>>,
 }
 
-struct UsbHandle {}
+#[derive(Debug, Clone)]
+pub struct UsbDeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus: u8,
+    pub address: u8,
+    // Physical location in sysfs form, e.g. "1-2.3"
+    pub port_path: String,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    // The descriptor's device release number, which ViaEMS sets to its
+    // firmware version
+    pub device_version: String,
+}
 
-impl<T: UsbContext> rusb::Hotplug<T> for UsbHandle {
-    fn device_arrived(&mut self, device: Device<T>) {
-        println!("ARRIVED {:?}", device);
+// Chooses which ECU a UsbConnection talks to. Without any filters the first
+// device with a matching VID/PID is used.
+#[derive(Debug, Clone)]
+pub struct UsbConnectionBuilder {
+    vendor_id: u16,
+    product_id: u16,
+    serial: Option<String>,
+    bus_address: Option<(u8, u8)>,
+    port_path: Option<String>,
+    detach_kernel_driver: bool,
+}
+
+// The sysfs name for the device plugged into `ports` on `bus`, e.g. "1-2.3"
+fn port_path(bus: u8, ports: &[u8]) -> String {
+    let ports = ports.iter()
+        .map(|p| p.to_string())
+        .collect::<Vec<String>>()
+        .join(".");
+    format!("{bus}-{ports}")
+}
+
+impl UsbConnectionBuilder {
+    pub fn vid_pid(mut self, vendor_id: u16, product_id: u16) -> Self {
+        self.vendor_id = vendor_id;
+        self.product_id = product_id;
+        self
     }
-    fn device_left(&mut self, device: Device<T>) {
-        println!("LEFT {:?}", device);
+
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_string());
+        self
     }
-}
 
-impl UsbConnection {
-    pub fn new() -> UsbConnection {
-//            let bleh = HotplugBuilder::new()
-//                .vendor_id(0x0483)
-//                .product_id(0x5740)
-//                .enumerate(true)
-//                .register::<Context, _>(&context, Box::new(UsbHandle{})).unwrap();
-//
-//            move || {
-//                let bleh = bleh;
-//                loop {
-//                    context.handle_events(None).unwrap();
-//                }
-//            }});
-        let context = Context::new().unwrap();
-        let mut devh = context.open_device_with_vid_pid(0x0483, 0x5740).expect("Could not open device");
-        for i in 0..=2 {
-            if devh.kernel_driver_active(i).unwrap() {
-                devh.detach_kernel_driver(i).expect("Could not detach kernel from device");
+    // The address changes every time the device re-enumerates, so prefer
+    // `port_path` when the device is expected to reset
+    pub fn bus_address(mut self, bus: u8, address: u8) -> Self {
+        self.bus_address = Some((bus, address));
+        self
+    }
+
+    pub fn port_path(mut self, path: &str) -> Self {
+        self.port_path = Some(path.to_string());
+        self
+    }
+
+    // Whether to detach kernel drivers (e.g. cdc_acm) bound to the device.
+    // Enabled by default.
+    pub fn detach_kernel_driver(mut self, detach: bool) -> Self {
+        self.detach_kernel_driver = detach;
+        self
+    }
+
+    // Location based filters, which don't need the device opened
+    fn matches_location(&self, info: &UsbDeviceInfo) -> bool {
+        if info.vendor_id != self.vendor_id || info.product_id != self.product_id {
+            return false;
+        }
+        if let Some((bus, address)) = self.bus_address {
+            if info.bus != bus || info.address != address {
+                return false;
             }
         }
+        if let Some(path) = &self.port_path {
+            if info.port_path != *path {
+                return false;
+            }
+        }
+        true
+    }
 
-        let devh = Arc::new(devh);
-        let running = Arc::new(atomic::AtomicBool::new(true));
+    fn matches_serial(&self, info: &UsbDeviceInfo) -> bool {
+        match &self.serial {
+            Some(serial) => info.serial.as_ref() == Some(serial),
+            None => true,
+        }
+    }
 
-        let (recv_tx, recv_rx) = mpsc::channel();
-        let recv_thread = std::thread::spawn({
-            let mut pool = TransferPool::new(devh.clone()).expect("could not create pool");
-            let running = running.clone();
-            move || {
-                for _ in 1..=4 {
-                    let mut buf : Vec<u8> = vec![];
-                    buf.reserve(16384);
-                    pool.submit_bulk(0x82, buf).unwrap();
+    fn describe<T: UsbContext>(device: &Device<T>, devh: Option<&DeviceHandle<T>>) -> Option<UsbDeviceInfo> {
+        let desc = device.device_descriptor().ok()?;
+        let read = |f: fn(&DeviceHandle<T>, &rusb::DeviceDescriptor) -> rusb::Result<String>| {
+            devh.and_then(|h| f(h, &desc).ok())
+        };
+        Some(UsbDeviceInfo {
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            bus: device.bus_number(),
+            address: device.address(),
+            port_path: port_path(device.bus_number(), &device.port_numbers().unwrap_or_default()),
+            manufacturer: read(DeviceHandle::read_manufacturer_string_ascii),
+            product: read(DeviceHandle::read_product_string_ascii),
+            serial: read(DeviceHandle::read_serial_number_string_ascii),
+            device_version: desc.device_version().to_string(),
+        })
+    }
+
+    // Lists the devices this builder would select from. String descriptors
+    // are only available for devices that could be opened.
+    pub fn list_devices(&self) -> Result<Vec<UsbDeviceInfo>, Error> {
+        let context = Context::new()?;
+        let mut found = vec![];
+        for device in context.devices()?.iter() {
+            if !Self::describe(&device, None).is_some_and(|info| self.matches_location(&info)) {
+                continue;
+            }
+            let devh = device.open().ok();
+            if let Some(info) = Self::describe(&device, devh.as_ref()) {
+                if self.matches_serial(&info) {
+                    found.push(info);
                 }
-                loop {
-                    if !running.load(atomic::Ordering::Relaxed) {
-                      break;
-                    }
-                    match pool.poll(Duration::from_secs(1)) {
-                        Ok(bytes) => {
-                            match serde_cbor::de::from_slice(&bytes[..]) {
-                              Ok(payload) => {
-                                let time = SystemTime::now();
-                                if recv_tx.send(RxMessage{time, payload}).is_err() { break; }
-                                pool.submit_bulk(0x82, bytes).unwrap();
-                              },
-                              Err(e) => println!("Failed to decode! {e}. Source: {bytes:?}"),
-                            }
-                        },
-                        Err(e) => {
-                          println!("{e:?}"); 
-                          break;
-                        },
+            }
+        }
+        Ok(found)
+    }
+
+    // Ok(None) if no matching device is present
+    fn open(&self, context: &Context) -> Result<Option<DeviceHandle<Context>>, Error> {
+        let devices = context.devices()?;
+        let devh = devices.iter()
+            .filter(|device| Self::describe(device, None).is_some_and(|info| self.matches_location(&info)))
+            .filter_map(|device| {
+                let devh = device.open().ok()?;
+                if self.serial.is_some() {
+                    let info = Self::describe(&device, Some(&devh))?;
+                    if !self.matches_serial(&info) {
+                        return None;
                     }
+                }
+                Some(devh)
+            })
+            .next();
+        let Some(devh) = devh else { return Ok(None) };
 
+        if self.detach_kernel_driver {
+            let config = devh.device().active_config_descriptor()?;
+            for interface in config.interfaces() {
+                let i = interface.number();
+                if devh.kernel_driver_active(i).unwrap_or(false) {
+                    devh.detach_kernel_driver(i)?;
                 }
             }
+        }
+        Ok(Some(devh))
+    }
+
+    pub fn build(self) -> Result<UsbConnection, Error> {
+        let context = Context::new()?;
+        let handle : SharedHandle = Arc::new(Mutex::new(None));
+        let running = Arc::new(atomic::AtomicBool::new(true));
+        let opened = OpenedDevice::default();
+
+        let (recv_tx, recv_rx) = mpsc::channel();
+        let errors = recv_tx.clone();
+        let recv_thread = std::thread::spawn({
+            let handle = handle.clone();
+            let running = running.clone();
+            let opened = opened.clone();
+            move || UsbConnection::recv_loop(self, context, handle, opened, running, recv_tx)
         });
 
         let (send_tx, send_rx) = mpsc::channel::<interface::Message>();
         let send_thread = std::thread::spawn({
             let running = running.clone();
-            move || {
-                loop {
-                    if !running.load(atomic::Ordering::Relaxed) {
-                      break;
+            move || UsbConnection::send_loop(handle, running, send_rx, errors)
+        });
+
+        Ok(UsbConnection {
+          recv_rx,
+          send_tx,
+          running,
+          opened,
+          recv_thread: Some(recv_thread),
+          send_thread: Some(send_thread),
+        })
+    }
+}
+
+impl Default for UsbConnectionBuilder {
+    fn default() -> Self {
+        UsbConnectionBuilder {
+            vendor_id: DEFAULT_VENDOR_ID,
+            product_id: DEFAULT_PRODUCT_ID,
+            serial: None,
+            bus_address: None,
+            port_path: None,
+            detach_kernel_driver: true,
+        }
+    }
+}
+
+struct UsbHandle {
+    arrived: Arc<atomic::AtomicBool>,
+}
+
+impl<T: UsbContext> rusb::Hotplug<T> for UsbHandle {
+    fn device_arrived(&mut self, _device: Device<T>) {
+        self.arrived.store(true, atomic::Ordering::Relaxed);
+    }
+    fn device_left(&mut self, _device: Device<T>) {
+        // Noticed by the transfers failing
+    }
+}
+
+enum Session {
+    Stopped,
+    Lost,
+}
+
+// rusb_async doesn't export its error type, so its variants can't be
+// matched. This relies on the derived Debug output of `Error::PollTimeout` in
+// rusb-async 0.0.1-alpha, which Cargo.toml pins; check it when upgrading.
+fn is_poll_timeout(e: &impl std::fmt::Debug) -> bool {
+    format!("{e:?}") == "PollTimeout"
+}
+
+// Sends a link change unless it repeats the last one. Returns false once the
+// receiving side has gone away.
+fn report(tx: &mpsc::Sender<RxEvent>, status: &mut Option<LinkStatus>, new_status: LinkStatus) -> bool {
+    if *status == Some(new_status) {
+        return true;
+    }
+    *status = Some(new_status);
+    tx.send(RxEvent::Link(new_status)).is_ok()
+}
+
+impl UsbConnection {
+    pub fn new() -> Result<UsbConnection, Error> {
+        UsbConnection::builder().build()
+    }
+
+    pub fn builder() -> UsbConnectionBuilder {
+        UsbConnectionBuilder::default()
+    }
+
+    // Lists ECUs with the default VID/PID
+    pub fn list_devices() -> Result<Vec<UsbDeviceInfo>, Error> {
+        UsbConnection::builder().list_devices()
+    }
+
+    // Follows which device this connection has open, and keeps doing so
+    // after the connection is handed to a Manager
+    pub fn opened_device(&self) -> OpenedDevice {
+        self.opened.clone()
+    }
+
+    // Blocks until the device may have arrived, or the rescan interval passes
+    fn wait_for_device(context: &Context, arrived: &atomic::AtomicBool, running: &atomic::AtomicBool) {
+        let deadline = Instant::now() + RESCAN_INTERVAL;
+        while Instant::now() < deadline && running.load(atomic::Ordering::Relaxed) {
+            if rusb::has_hotplug() {
+                let _ = context.handle_events(Some(Duration::from_millis(100)));
+                if arrived.swap(false, atomic::Ordering::Relaxed) {
+                    return;
+                }
+            } else {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    fn recv_loop(selector: UsbConnectionBuilder, context: Context, handle: SharedHandle, opened: OpenedDevice,
+                 running: Arc<atomic::AtomicBool>, tx: mpsc::Sender<RxEvent>) {
+        let arrived = Arc::new(atomic::AtomicBool::new(false));
+        let _registration = if rusb::has_hotplug() {
+            HotplugBuilder::new()
+                .vendor_id(selector.vendor_id)
+                .product_id(selector.product_id)
+                .register::<Context, _>(&context, Box::new(UsbHandle{ arrived: arrived.clone() }))
+                .ok()
+        } else {
+            None
+        };
+
+        let mut status = None;
+        // Only report the first failure to open while waiting, as it is
+        // likely to repeat on every rescan
+        let mut open_failed = false;
+
+        while running.load(atomic::Ordering::Relaxed) {
+            let devh = match selector.open(&context) {
+                Ok(Some(devh)) => Arc::new(devh),
+                result => {
+                    if let Err(e) = result {
+                        if !open_failed && tx.send(RxEvent::Error(e)).is_err() { break; }
+                        open_failed = true;
                     }
-                    match send_rx.recv_timeout(Duration::from_millis(100)) {
-                        Ok(msg) => {
-                            let bytes = serde_cbor::to_vec(&msg).unwrap();
-                            devh.write_bulk(0x01, &bytes[..], Duration::from_secs(1)).unwrap();
+                    if !report(&tx, &mut status, LinkStatus::Disconnected) { break; }
+                    UsbConnection::wait_for_device(&context, &arrived, &running);
+                    continue;
+                },
+            };
+            open_failed = false;
+            if status == Some(LinkStatus::Disconnected) && !report(&tx, &mut status, LinkStatus::Reconnecting) { break; }
+
+            opened.set(UsbConnectionBuilder::describe(&devh.device(), Some(&devh)));
+            *handle.lock().unwrap() = Some(devh.clone());
+            if !report(&tx, &mut status, LinkStatus::Connected) { break; }
+            let session = UsbConnection::read_device(devh, &running, &tx);
+            *handle.lock().unwrap() = None;
+            opened.set(None);
+
+            match session {
+                Session::Stopped => break,
+                Session::Lost => {
+                    if !report(&tx, &mut status, LinkStatus::Disconnected) { break; }
+                },
+            }
+        }
+    }
+
+    fn read_device(devh: Arc<DeviceHandle<Context>>, running: &atomic::AtomicBool, tx: &mpsc::Sender<RxEvent>) -> Session {
+        let lost = |what: &str, e: &dyn std::fmt::Display| {
+            let _ = tx.send(RxEvent::Error(Error::Other(format!("{what}: {e}"))));
+            Session::Lost
+        };
+        let mut pool = match TransferPool::new(devh) {
+            Ok(pool) => pool,
+            Err(e) => return lost("Could not create transfer pool", &e),
+        };
+        for _ in 1..=4 {
+            let buf : Vec<u8> = Vec::with_capacity(16384);
+            if let Err(e) = pool.submit_bulk(0x82, buf) {
+                return lost("Could not submit transfer", &e);
+            }
+        }
+        loop {
+            if !running.load(atomic::Ordering::Relaxed) {
+              return Session::Stopped;
+            }
+            match pool.poll(Duration::from_millis(100)) {
+                Ok(bytes) => {
+                    match serde_cbor::de::from_slice(&bytes[..]) {
+                      Ok(payload) => {
+                        let time = SystemTime::now();
+                        if tx.send(RxEvent::Message(RxMessage{time, payload})).is_err() {
+                          return Session::Stopped;
                         }
-                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
-                        _ => break,
+                      },
+                      Err(e) => {
+                        if tx.send(RxEvent::Error(Error::Decode(e.to_string()))).is_err() {
+                          return Session::Stopped;
+                        }
+                      },
                     }
-                }
+                    if let Err(e) = pool.submit_bulk(0x82, bytes) {
+                      return lost("Could not submit transfer", &e);
+                    }
+                },
+                Err(e) if is_poll_timeout(&e) => continue,
+                Err(e) => return lost("Transfer failed", &e),
             }
-        });
-
+        }
+    }
 
-        UsbConnection { 
-          recv_rx, 
-          send_tx, 
-          running, 
-          recv_thread: Some(recv_thread),
-          send_thread: Some(send_thread),
+    fn send_loop(handle: SharedHandle, running: Arc<atomic::AtomicBool>,
+                 send_rx: mpsc::Receiver<interface::Message>, errors: mpsc::Sender<RxEvent>) {
+        loop {
+            if !running.load(atomic::Ordering::Relaxed) {
+              break;
+            }
+            match send_rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(msg) => {
+                    // Messages sent while the device is away are dropped, the
+                    // Manager's retries cover requests
+                    let devh = handle.lock().unwrap().clone();
+                    if let Some(devh) = devh {
+                        let sent = serde_cbor::to_vec(&msg)
+                            .map_err(Error::from)
+                            .and_then(|bytes| Ok(devh.write_bulk(0x01, &bytes[..], Duration::from_secs(1))?));
+                        if let Err(e) = sent {
+                            let _ = errors.send(RxEvent::Error(e));
+                        }
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                _ => break,
+            }
         }
     }
 }
@@ -130,11 +453,90 @@ impl Drop for UsbConnection {
 
 
 impl Connection for UsbConnection {
-    fn recv(&self, timeout: Duration) -> Result<RxMessage, ConnError> {
-      return Ok(self.recv_rx.recv_timeout(timeout)?);
+    fn recv(&self, timeout: Duration) -> Result<RxEvent, ConnError> {
+      Ok(self.recv_rx.recv_timeout(timeout)?)
     }
 
     fn get_writer(&self) -> Writer {
         Writer { tx: self.send_tx.clone() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_only_link_changes() {
+        let (tx, rx) = mpsc::channel();
+        let mut status = None;
+        for new_status in [LinkStatus::Disconnected, LinkStatus::Disconnected, LinkStatus::Reconnecting,
+                           LinkStatus::Connected, LinkStatus::Connected, LinkStatus::Disconnected] {
+            assert!(report(&tx, &mut status, new_status));
+        }
+        let reported = rx.try_iter()
+            .map(|event| match event {
+                RxEvent::Link(status) => status,
+                _ => panic!("expected a link change"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(reported, [LinkStatus::Disconnected, LinkStatus::Reconnecting,
+                              LinkStatus::Connected, LinkStatus::Disconnected]);
+
+        drop(rx);
+        assert!(report(&tx, &mut status, LinkStatus::Disconnected));
+        assert!(!report(&tx, &mut status, LinkStatus::Connected));
+    }
+
+    fn info(serial: Option<&str>) -> UsbDeviceInfo {
+        UsbDeviceInfo {
+            vendor_id: DEFAULT_VENDOR_ID,
+            product_id: DEFAULT_PRODUCT_ID,
+            bus: 1,
+            address: 4,
+            port_path: "1-2.3".to_string(),
+            manufacturer: None,
+            product: None,
+            serial: serial.map(str::to_string),
+            device_version: "1.0.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn serial_filter() {
+        let any = UsbConnection::builder();
+        assert!(any.matches_serial(&info(Some("A1"))));
+        assert!(any.matches_serial(&info(None)));
+
+        let one = UsbConnection::builder().serial("A1");
+        assert!(one.matches_serial(&info(Some("A1"))));
+        assert!(!one.matches_serial(&info(Some("B2"))));
+        // Devices that couldn't be opened have no serial to compare
+        assert!(!one.matches_serial(&info(None)));
+    }
+
+    #[test]
+    fn location_filters() {
+        let device = info(None);
+        assert!(UsbConnection::builder().matches_location(&device));
+        assert!(!UsbConnection::builder().vid_pid(0x1209, DEFAULT_PRODUCT_ID).matches_location(&device));
+        assert!(!UsbConnection::builder().vid_pid(DEFAULT_VENDOR_ID, 0x0001).matches_location(&device));
+
+        assert!(UsbConnection::builder().bus_address(1, 4).matches_location(&device));
+        assert!(!UsbConnection::builder().bus_address(1, 5).matches_location(&device));
+        assert!(!UsbConnection::builder().bus_address(2, 4).matches_location(&device));
+
+        assert!(UsbConnection::builder().port_path("1-2.3").matches_location(&device));
+        assert!(!UsbConnection::builder().port_path("1-2").matches_location(&device));
+
+        // Every filter has to match
+        let both = UsbConnection::builder().bus_address(1, 4).port_path("1-2.4");
+        assert!(!both.matches_location(&device));
+    }
+
+    #[test]
+    fn port_paths() {
+        assert_eq!(port_path(1, &[2]), "1-2");
+        assert_eq!(port_path(3, &[1, 4, 2]), "3-1.4.2");
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum CommandError {
+  Rejected(String),
+  Timeout { id: u32, attempts: u32 },
+  Disconnected,
+}
+
+impl fmt::Display for CommandError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CommandError::Rejected(reason) => write!(f, "request rejected by device: {reason}"),
+      CommandError::Timeout { id, attempts } =>
+        write!(f, "request {id} timed out after {attempts} attempts"),
+      CommandError::Disconnected => write!(f, "connection closed before a response arrived"),
+    }
+  }
+}
+
+impl std::error::Error for CommandError {}
+
+#[derive(Debug)]
+pub enum Error {
+  Io(std::io::Error),
+  Usb(rusb::Error),
+  Serial(serialport::Error),
+  Sqlite(sqlite::Error),
+  Cbor(serde_cbor::Error),
+  Json(serde_json::Error),
+  Command(CommandError),
+  // Data from the device that couldn't be decoded as a message, or a
+  // damaged file
+  Decode(String),
+  // A feed whose number of values doesn't match the current description
+  FeedLength { expected: usize, received: usize },
+  // The other end of a connection or background thread has gone away
+  Disconnected,
+  // Anything else, e.g. a log file that wasn't written by LogFeedWriter
+  Other(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Io(e) => write!(f, "I/O error: {e}"),
+      Error::Usb(e) => write!(f, "USB error: {e}"),
+      Error::Serial(e) => write!(f, "serial port error: {e}"),
+      Error::Sqlite(e) => write!(f, "sqlite error: {e}"),
+      Error::Cbor(e) => write!(f, "CBOR error: {e}"),
+      Error::Json(e) => write!(f, "JSON error: {e}"),
+      Error::Command(e) => e.fmt(f),
+      Error::Decode(reason) => write!(f, "undecodable message: {reason}"),
+      Error::FeedLength { expected, received } =>
+        write!(f, "feed has {received} values but the description has {expected}"),
+      Error::Disconnected => write!(f, "disconnected"),
+      Error::Other(reason) => f.write_str(reason),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io(e) => Some(e),
+      Error::Usb(e) => Some(e),
+      Error::Serial(e) => Some(e),
+      Error::Sqlite(e) => Some(e),
+      Error::Cbor(e) => Some(e),
+      Error::Json(e) => Some(e),
+      Error::Command(e) => Some(e),
+      Error::Decode(_) | Error::FeedLength { .. } | Error::Disconnected | Error::Other(_) => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(e: std::io::Error) -> Error { Error::Io(e) }
+}
+
+impl From<rusb::Error> for Error {
+  fn from(e: rusb::Error) -> Error { Error::Usb(e) }
+}
+
+impl From<serialport::Error> for Error {
+  fn from(e: serialport::Error) -> Error { Error::Serial(e) }
+}
+
+impl From<sqlite::Error> for Error {
+  fn from(e: sqlite::Error) -> Error { Error::Sqlite(e) }
+}
+
+impl From<serde_cbor::Error> for Error {
+  fn from(e: serde_cbor::Error) -> Error { Error::Cbor(e) }
+}
+
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Error { Error::Json(e) }
+}
+
+impl From<CommandError> for Error {
+  fn from(e: CommandError) -> Error { Error::Command(e) }
+}
@@ -0,0 +1,90 @@
+use std::io::Write;
+
+use crate::export::{seconds_since, ExportOptions};
+use crate::interface::FeedValue;
+use crate::{Error, LogReader};
+
+// Quotes a header field if a spreadsheet would otherwise split it
+pub fn csv_field(name: &str) -> String {
+  if name.contains([',', '"', '\n', '\r']) {
+    format!("\"{}\"", name.replace('"', "\"\""))
+  } else {
+    name.to_string()
+  }
+}
+
+// Writes a header row of `time` and the channel names, then one row per
+// point. Times are in seconds. Missing values are left empty.
+pub fn export_csv<W: Write>(reader: &LogReader, options: &ExportOptions, out: W) -> Result<(), Error> {
+  let mut out = std::io::BufWriter::new(out);
+  let epoch = options.epoch(reader)?;
+  let (schema, frames) = options.read(reader)?;
+  write!(out, "time")?;
+  for key in schema.keys() {
+    write!(out, ",{}", csv_field(key))?;
+  }
+  writeln!(out)?;
+  for frame in frames {
+    let frame = frame?;
+    write!(out, "{:.6}", seconds_since(epoch, frame.time()))?;
+    for value in frame.values() {
+      match value {
+        FeedValue::Int(v) => write!(out, ",{v}")?,
+        FeedValue::Float(v) if v.is_nan() => write!(out, ",")?,
+        FeedValue::Float(v) => write!(out, ",{v}")?,
+      }
+    }
+    writeln!(out)?;
+  }
+  out.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::export::TimeFormat;
+  use crate::testing;
+
+  fn export(options: &ExportOptions) -> Result<String, Error> {
+    let log = testing::write_log(&["rpm", "map,kpa"], &[
+      (0, vec![FeedValue::Int(800), FeedValue::Float(30.5)]),
+      (250, vec![FeedValue::Int(900), FeedValue::Float(31.0)]),
+    ]);
+    let mut out = vec![];
+    export_csv(&LogReader::open(log.path())?, options, &mut out)?;
+    Ok(String::from_utf8(out).unwrap())
+  }
+
+  #[test]
+  fn quotes_csv_fields() {
+    assert_eq!(csv_field("rpm"), "rpm");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+  }
+
+  #[test]
+  fn writes_header_and_rows() {
+    assert_eq!(export(&ExportOptions::default()).unwrap(),
+               "time,rpm,\"map,kpa\"\n0.000000,800,30.5\n0.250000,900,31\n");
+  }
+
+  #[test]
+  fn selects_resamples_and_uses_absolute_time() {
+    let options = ExportOptions {
+      channels: Some(vec!["rpm".to_string()]),
+      time: TimeFormat::Absolute,
+      resample: Some(10.0),
+      ..Default::default()
+    };
+    // No sample falls after the last point at 250ms
+    assert_eq!(export(&options).unwrap().lines().collect::<Vec<_>>(),
+               ["time,rpm", "1700000000.000000,800", "1700000000.100000,800", "1700000000.200000,800"]);
+  }
+
+  #[test]
+  fn rejects_unusable_rates() {
+    let options = ExportOptions { resample: Some(f64::INFINITY), ..Default::default() };
+    assert!(export(&options).is_err());
+  }
+}
@@ -0,0 +1,527 @@
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::export::{channel_types, seconds_since, ExportOptions};
+use crate::interface::FeedValue;
+use crate::{ChannelType, Error, FeedFrame, FeedSchema, LogReader};
+
+// ASAM MDF 4.1. Every block starts with a 24 byte header (id, reserved,
+// length, link count) followed by its links and data, is 8 byte aligned, and
+// all numbers are little endian.
+const HEADER_SIZE: u64 = 24;
+const MDF_VERSION: u16 = 410;
+
+const CN_TYPE_FIXED: u8 = 0;
+const CN_TYPE_MASTER: u8 = 2;
+const CN_SYNC_NONE: u8 = 0;
+const CN_SYNC_TIME: u8 = 1;
+const CN_FLAG_INVAL_VALID: u32 = 0x02;
+
+const DATA_TYPE_UINT: u8 = 0;
+const DATA_TYPE_INT: u8 = 2;
+const DATA_TYPE_FLOAT: u8 = 4;
+
+// Links are patched in once the blocks they point to exist
+const CG_LINK_CN_FIRST: usize = 1;
+const CN_LINK_NEXT: usize = 0;
+
+fn invalid(reason: &str) -> Error {
+  Error::Other(format!("unsupported MDF file: {reason}"))
+}
+
+// Lays out the metadata blocks in memory, so that they can be linked to each
+// other before being written out
+struct Blocks {
+  buf: Vec<u8>,
+}
+
+impl Blocks {
+  fn block(&mut self, id: &[u8; 4], links: &[u64], data: &[u8]) -> u64 {
+    let at = self.buf.len() as u64;
+    let length = HEADER_SIZE + 8 * links.len() as u64 + data.len() as u64;
+    self.buf.extend_from_slice(id);
+    self.buf.extend_from_slice(&[0; 4]);
+    self.buf.extend_from_slice(&length.to_le_bytes());
+    self.buf.extend_from_slice(&(links.len() as u64).to_le_bytes());
+    for link in links {
+      self.buf.extend_from_slice(&link.to_le_bytes());
+    }
+    self.buf.extend_from_slice(data);
+    self.buf.resize(self.buf.len().next_multiple_of(8), 0);
+    at
+  }
+
+  fn text(&mut self, id: &[u8; 4], text: &str) -> u64 {
+    let mut data = text.as_bytes().to_vec();
+    data.push(0);
+    self.block(id, &[], &data)
+  }
+
+  fn set_link(&mut self, block: u64, index: usize, target: u64) {
+    let at = (block + HEADER_SIZE) as usize + 8 * index;
+    self.buf[at..at + 8].copy_from_slice(&target.to_le_bytes());
+  }
+}
+
+struct Channel<'a> {
+  name: &'a str,
+  units: Option<&'a str>,
+  cn_type: u8,
+  sync_type: u8,
+  data_type: u8,
+  byte_offset: u32,
+  bit_count: u32,
+  inval_bit: Option<u32>,
+}
+
+impl Channel<'_> {
+  fn write(&self, blocks: &mut Blocks) -> u64 {
+    let name = blocks.text(b"##TX", self.name);
+    let units = self.units.map_or(0, |u| blocks.text(b"##TX", u));
+    let mut data = vec![self.cn_type, self.sync_type, self.data_type, 0];
+    data.extend_from_slice(&self.byte_offset.to_le_bytes());
+    data.extend_from_slice(&self.bit_count.to_le_bytes());
+    let flags = if self.inval_bit.is_some() { CN_FLAG_INVAL_VALID } else { 0 };
+    data.extend_from_slice(&flags.to_le_bytes());
+    data.extend_from_slice(&self.inval_bit.unwrap_or(0).to_le_bytes());
+    // Precision, reserved, attachment count
+    data.extend_from_slice(&[0, 0, 0, 0]);
+    // Value range and limits, unused as their flags are clear
+    data.extend_from_slice(&[0; 48]);
+    blocks.block(b"##CN", &[0, 0, name, 0, 0, 0, units, 0], &data)
+  }
+}
+
+// Writes an MDF4 file with a single channel group: a master `time` channel
+// in seconds from the start of the log, whose absolute time is in the file
+// header, followed by the selected channels. Integer channels mark their
+// missing values with invalidation bits. Needs to seek back to fill in the
+// record count, so can't write to a pipe.
+pub fn export_mdf4<W: Write + Seek>(reader: &LogReader, options: &ExportOptions, mut out: W) -> Result<(), Error> {
+  let (schema, frames) = options.read(reader)?;
+  let types = channel_types(reader, &schema);
+  let start = reader.time_span()?.map_or(SystemTime::UNIX_EPOCH, |(first, _)| first);
+  let start_ns = start.duration_since(SystemTime::UNIX_EPOCH)
+    .map_err(|e| Error::Other(e.to_string()))?
+    .as_nanos() as u64;
+
+  let data_bytes = 8 + 4 * schema.len() as u32;
+  let int_count = types.iter().filter(|t| **t == ChannelType::Int).count() as u32;
+  let inval_bytes = int_count.div_ceil(8);
+
+  let mut channels = vec![Channel {
+    name: "time", units: Some("s"), cn_type: CN_TYPE_MASTER, sync_type: CN_SYNC_TIME,
+    data_type: DATA_TYPE_FLOAT, byte_offset: 0, bit_count: 64, inval_bit: None,
+  }];
+  let mut inval_bits = vec![];
+  for (i, (key, channel_type)) in schema.keys().iter().zip(&types).enumerate() {
+    let (data_type, inval_bit) = match channel_type {
+      ChannelType::Int => (DATA_TYPE_UINT, Some(inval_bits.len() as u32)),
+      ChannelType::Float => (DATA_TYPE_FLOAT, None),
+    };
+    if let Some(bit) = inval_bit {
+      inval_bits.push((i, bit));
+    }
+    channels.push(Channel {
+      name: key, units: options.units.get(key).map(String::as_str),
+      cn_type: CN_TYPE_FIXED, sync_type: CN_SYNC_NONE, data_type,
+      byte_offset: 8 + 4 * i as u32, bit_count: 32, inval_bit,
+    });
+  }
+
+  let mut blocks = Blocks { buf: Vec::new() };
+
+  let mut id = Vec::with_capacity(64);
+  id.extend_from_slice(b"MDF     4.10    viaems  ");
+  id.extend_from_slice(&[0; 4]);
+  id.extend_from_slice(&MDF_VERSION.to_le_bytes());
+  id.resize(64, 0);
+  blocks.buf.extend_from_slice(&id);
+
+  let mut hd = start_ns.to_le_bytes().to_vec();
+  // UTC with no timezone or DST offsets, no start angle or distance
+  hd.extend_from_slice(&[0; 24]);
+  let hd_at = blocks.block(b"##HD", &[0; 6], &hd);
+
+  let comment = format!("<FHcomment><TX>Exported from a viaems log</TX>\
+    <tool_id>viaems</tool_id><tool_vendor>viaems</tool_vendor>\
+    <tool_version>{}</tool_version></FHcomment>", env!("CARGO_PKG_VERSION"));
+  let fh_comment = blocks.text(b"##MD", &comment);
+  let now_ns = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+  let mut fh = now_ns.to_le_bytes().to_vec();
+  fh.extend_from_slice(&[0; 8]);
+  let fh_at = blocks.block(b"##FH", &[0, fh_comment], &fh);
+
+  let dg_at = blocks.block(b"##DG", &[0; 4], &[0; 8]);
+
+  let mut cg = Vec::with_capacity(32);
+  // Record id, cycle count (filled in at the end), flags, path separator,
+  // reserved
+  cg.extend_from_slice(&[0; 24]);
+  cg.extend_from_slice(&data_bytes.to_le_bytes());
+  cg.extend_from_slice(&inval_bytes.to_le_bytes());
+  let cg_at = blocks.block(b"##CG", &[0; 6], &cg);
+
+  let mut previous : Option<u64> = None;
+  for channel in &channels {
+    let cn_at = channel.write(&mut blocks);
+    match previous {
+      None => blocks.set_link(cg_at, CG_LINK_CN_FIRST, cn_at),
+      Some(prev) => blocks.set_link(prev, CN_LINK_NEXT, cn_at),
+    }
+    previous = Some(cn_at);
+  }
+
+  let dt_at = blocks.buf.len() as u64;
+  blocks.set_link(hd_at, 0, dg_at);
+  blocks.set_link(hd_at, 1, fh_at);
+  blocks.set_link(dg_at, 1, cg_at);
+  blocks.set_link(dg_at, 2, dt_at);
+
+  let mut writer = std::io::BufWriter::new(&mut out);
+  writer.write_all(&blocks.buf)?;
+  // The length is filled in once the records are written
+  writer.write_all(b"##DT")?;
+  writer.write_all(&[0; 20])?;
+
+  let mut record = Vec::with_capacity((data_bytes + inval_bytes) as usize);
+  let mut count : u64 = 0;
+  for frame in frames {
+    let frame = frame?;
+    record.clear();
+    record.extend_from_slice(&seconds_since(start, frame.time()).to_le_bytes());
+    for (value, channel_type) in frame.values().iter().zip(&types) {
+      match (channel_type, value) {
+        (ChannelType::Int, FeedValue::Int(v)) => record.extend_from_slice(&v.to_le_bytes()),
+        (ChannelType::Int, FeedValue::Float(_)) => record.extend_from_slice(&0u32.to_le_bytes()),
+        (ChannelType::Float, FeedValue::Int(v)) => record.extend_from_slice(&(*v as f32).to_le_bytes()),
+        (ChannelType::Float, FeedValue::Float(v)) => record.extend_from_slice(&v.to_le_bytes()),
+      }
+    }
+    let mut inval = vec![0u8; inval_bytes as usize];
+    for (i, bit) in &inval_bits {
+      if let FeedValue::Float(_) = frame.values()[*i] {
+        inval[(bit / 8) as usize] |= 1 << (bit % 8);
+      }
+    }
+    record.extend_from_slice(&inval);
+    writer.write_all(&record)?;
+    count += 1;
+  }
+  writer.flush()?;
+  drop(writer);
+
+  let dt_length = HEADER_SIZE + count * (data_bytes + inval_bytes) as u64;
+  out.seek(SeekFrom::Start(dt_at + 8))?;
+  out.write_all(&dt_length.to_le_bytes())?;
+  out.seek(SeekFrom::Start(cg_at + HEADER_SIZE + 6 * 8 + 8))?;
+  out.write_all(&count.to_le_bytes())?;
+  out.seek(SeekFrom::End(0))?;
+  out.flush()?;
+  Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct MdfChannel {
+  pub name: String,
+  pub units: Option<String>,
+  pub channel_type: ChannelType,
+}
+
+// The contents of an MDF file, as read by `read_mdf4`
+#[derive(Debug)]
+pub struct MdfLog {
+  pub start: SystemTime,
+  pub channels: Vec<MdfChannel>,
+  pub frames: Vec<FeedFrame>,
+}
+
+// Damage the reader can't make sense of, as opposed to valid MDF features
+// it doesn't support
+fn corrupt(reason: &str) -> Error {
+  Error::Decode(format!("corrupt MDF file: {reason}"))
+}
+
+struct RawBlock {
+  id: [u8; 4],
+  at: u64,
+  links: Vec<u64>,
+  data: Vec<u8>,
+}
+
+impl RawBlock {
+  fn link(&self, index: usize) -> Result<u64, Error> {
+    self.links.get(index).copied()
+      .ok_or_else(|| corrupt(&format!("block at {} is missing link {index}", self.at)))
+  }
+
+  fn bytes<const N: usize>(&self, at: usize) -> Result<[u8; N], Error> {
+    self.data.get(at..at + N)
+      .map(|bytes| bytes.try_into().unwrap())
+      .ok_or_else(|| corrupt(&format!("block at {} is too short", self.at)))
+  }
+
+  fn u8_at(&self, at: usize) -> Result<u8, Error> {
+    Ok(self.bytes::<1>(at)?[0])
+  }
+
+  fn u32_at(&self, at: usize) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(self.bytes(at)?))
+  }
+
+  fn u64_at(&self, at: usize) -> Result<u64, Error> {
+    Ok(u64::from_le_bytes(self.bytes(at)?))
+  }
+}
+
+// Checks every block against the size of the file before reading it, so a
+// bad link or length can't run past the end or ask for a huge buffer
+struct MdfInput<R> {
+  input: R,
+  len: u64,
+}
+
+impl<R: Read + Seek> MdfInput<R> {
+  fn block(&mut self, at: u64) -> Result<RawBlock, Error> {
+    if at.checked_add(HEADER_SIZE).is_none_or(|end| end > self.len) {
+      return Err(corrupt(&format!("block at {at} is outside the file")));
+    }
+    self.input.seek(SeekFrom::Start(at))?;
+    let mut header = [0u8; HEADER_SIZE as usize];
+    self.input.read_exact(&mut header)?;
+    let id : [u8; 4] = header[0..4].try_into().unwrap();
+    let length = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let link_count = u64::from_le_bytes(header[16..24].try_into().unwrap());
+    let links_size = link_count.checked_mul(8)
+      .filter(|size| size.checked_add(HEADER_SIZE).is_some_and(|min| min <= length));
+    let Some(links_size) = links_size.filter(|_| &id[0..2] == b"##") else {
+      return Err(corrupt(&format!("bad block at {at}")));
+    };
+    if length > self.len - at {
+      return Err(corrupt(&format!("block at {at} runs past the end of the file")));
+    }
+    let mut links = vec![0u8; links_size as usize];
+    self.input.read_exact(&mut links)?;
+    let mut data = vec![0u8; (length - HEADER_SIZE - links_size) as usize];
+    self.input.read_exact(&mut data)?;
+    Ok(RawBlock {
+      id,
+      at,
+      links: links.chunks_exact(8).map(|l| u64::from_le_bytes(l.try_into().unwrap())).collect(),
+      data,
+    })
+  }
+
+  fn text(&mut self, at: u64) -> Result<Option<String>, Error> {
+    if at == 0 {
+      return Ok(None);
+    }
+    let block = self.block(at)?;
+    let end = block.data.iter().position(|&b| b == 0).unwrap_or(block.data.len());
+    Ok(Some(String::from_utf8_lossy(&block.data[..end]).into_owned()))
+  }
+}
+
+// Only used on records, whose channels are checked to fit
+fn le_u32(data: &[u8], at: usize) -> u32 {
+  u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+// Reads the first channel group of an MDF4 file whose records are in a
+// single DT block, which covers files written by `export_mdf4`
+pub fn read_mdf4<R: Read + Seek>(mut input: R) -> Result<MdfLog, Error> {
+  let len = input.seek(SeekFrom::End(0))?;
+  input.seek(SeekFrom::Start(0))?;
+  let mut id = [0u8; 64];
+  input.read_exact(&mut id)?;
+  if &id[0..8] != b"MDF     " || u16::from_le_bytes([id[28], id[29]]) < 400 {
+    return Err(invalid("not an MDF4 file"));
+  }
+  let mut input = MdfInput { input, len };
+
+  let hd = input.block(64)?;
+  let start = SystemTime::UNIX_EPOCH + Duration::from_nanos(hd.u64_at(0)?);
+  let dg = input.block(hd.link(0)?)?;
+  if dg.u8_at(0)? != 0 {
+    return Err(invalid("record ids"));
+  }
+  let cg = input.block(dg.link(1)?)?;
+  let cycle_count = cg.u64_at(8)?;
+  let data_bytes = cg.u32_at(24)? as usize;
+  let record_size = data_bytes + cg.u32_at(28)? as usize;
+  if record_size == 0 {
+    return Err(corrupt("empty records"));
+  }
+
+  struct Layout { cn_type: u8, data_type: u8, byte_offset: usize, bit_count: u32, inval_bit: Option<u32> }
+  let mut channels = vec![];
+  let mut layouts = vec![];
+  let mut master = None;
+  let mut seen = HashSet::new();
+  let mut next = cg.link(CG_LINK_CN_FIRST)?;
+  while next != 0 {
+    if !seen.insert(next) {
+      return Err(corrupt("channel list loops back on itself"));
+    }
+    let cn = input.block(next)?;
+    next = cn.link(CN_LINK_NEXT)?;
+    let layout = Layout {
+      cn_type: cn.u8_at(0)?,
+      data_type: cn.u8_at(2)?,
+      byte_offset: cn.u32_at(4)? as usize,
+      bit_count: cn.u32_at(8)?,
+      inval_bit: if cn.u32_at(12)? & CN_FLAG_INVAL_VALID != 0 { Some(cn.u32_at(16)?) } else { None },
+    };
+    // What `read_number` reads, whatever the bit count says
+    let size = if (layout.data_type, layout.bit_count) == (DATA_TYPE_FLOAT, 64) { 8 } else { 4 };
+    if layout.byte_offset + size > data_bytes {
+      return Err(invalid("channel outside its record"));
+    }
+    if layout.inval_bit.is_some_and(|bit| data_bytes + (bit / 8) as usize >= record_size) {
+      return Err(corrupt("invalidation bit outside its record"));
+    }
+    if layout.cn_type == CN_TYPE_MASTER {
+      master = Some(layout);
+      continue;
+    }
+    channels.push(MdfChannel {
+      name: input.text(cn.link(2)?)?.unwrap_or_default(),
+      units: input.text(cn.link(6)?)?,
+      channel_type: if layout.data_type == DATA_TYPE_FLOAT { ChannelType::Float } else { ChannelType::Int },
+    });
+    layouts.push(layout);
+  }
+  let master = master.ok_or_else(|| invalid("no master channel"))?;
+
+  let dt = input.block(dg.link(2)?)?;
+  if &dt.id != b"##DT" {
+    return Err(invalid("records not in a single DT block"));
+  }
+
+  let read_number = |record: &[u8], layout: &Layout| -> f64 {
+    let bytes = &record[layout.byte_offset..];
+    match (layout.data_type, layout.bit_count) {
+      (DATA_TYPE_FLOAT, 64) => f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+      (DATA_TYPE_FLOAT, _) => f32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+      (DATA_TYPE_INT, _) => i32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+      _ => le_u32(bytes, 0) as f64,
+    }
+  };
+
+  let schema = Arc::new(FeedSchema::new(channels.iter().map(|c| c.name.clone()).collect()));
+  let records = dt.data.chunks_exact(record_size).take(cycle_count.try_into().unwrap_or(usize::MAX));
+  let mut frames = Vec::with_capacity(records.len());
+  for record in records {
+    let time = Duration::try_from_secs_f64(read_number(record, &master).max(0.0)).ok()
+      .and_then(|offset| start.checked_add(offset))
+      .ok_or_else(|| corrupt("record time out of range"))?;
+    let values = layouts.iter().zip(&channels).map(|(layout, channel)| {
+      let is_invalid = layout.inval_bit.is_some_and(|bit| {
+        record[data_bytes + (bit / 8) as usize] & (1 << (bit % 8)) != 0
+      });
+      match channel.channel_type {
+        _ if is_invalid => FeedValue::Float(f32::NAN),
+        ChannelType::Int => FeedValue::Int(le_u32(record, layout.byte_offset)),
+        ChannelType::Float => FeedValue::Float(read_number(record, layout) as f32),
+      }
+    }).collect();
+    frames.push(FeedFrame::new(time, schema.clone(), values)?);
+  }
+  Ok(MdfLog { start, channels, frames })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+  use crate::testing;
+
+  fn export() -> Vec<u8> {
+    let log = testing::write_log(&["rpm", "map"], &[
+      (0, vec![FeedValue::Int(800), FeedValue::Float(30.5)]),
+      (100, vec![FeedValue::Int(900), FeedValue::Float(31.0)]),
+      (250, vec![FeedValue::Float(f32::NAN), FeedValue::Float(32.0)]),
+    ]);
+    let options = ExportOptions {
+      units: [("map".to_string(), "kPa".to_string())].into_iter().collect(),
+      ..Default::default()
+    };
+    let mut out = Cursor::new(vec![]);
+    export_mdf4(&LogReader::open(log.path()).unwrap(), &options, &mut out).unwrap();
+    out.into_inner()
+  }
+
+  #[test]
+  fn round_trip() {
+    let mdf = read_mdf4(Cursor::new(export())).unwrap();
+    assert_eq!(mdf.start, testing::log_start());
+    let names = mdf.channels.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, ["rpm", "map"]);
+    assert_eq!(mdf.channels[0].channel_type, ChannelType::Int);
+    assert_eq!(mdf.channels[1].units.as_deref(), Some("kPa"));
+
+    let times = mdf.frames.iter()
+      .map(|f| f.time().duration_since(mdf.start).unwrap().as_millis())
+      .collect::<Vec<_>>();
+    assert_eq!(times, [0, 100, 250]);
+    assert_eq!(mdf.frames[0].values(), [FeedValue::Int(800), FeedValue::Float(30.5)]);
+    assert_eq!(mdf.frames[1].values(), [FeedValue::Int(900), FeedValue::Float(31.0)]);
+    // The missing integer comes back invalidated
+    assert!(matches!(mdf.frames[2].values(), [FeedValue::Float(rpm), FeedValue::Float(map)]
+                     if rpm.is_nan() && *map == 32.0));
+  }
+
+  #[test]
+  fn truncated_files_are_errors() {
+    let file = export();
+    for len in (0..file.len()).step_by(7) {
+      assert!(read_mdf4(Cursor::new(&file[..len])).is_err(), "{len}");
+    }
+  }
+
+  #[test]
+  fn bad_links_and_lengths_are_decode_errors() {
+    let file = export();
+    let hd_links = 64 + HEADER_SIZE as usize;
+    let corrupted = |at: usize, value: u64| {
+      let mut file = file.clone();
+      file[at..at + 8].copy_from_slice(&value.to_le_bytes());
+      read_mdf4(Cursor::new(file))
+    };
+    // HD's link to the data group, then its own length and link count
+    for (at, value) in [(hd_links, 1 << 40), (hd_links, 3), (64 + 8, u64::MAX), (64 + 16, u64::MAX / 4)] {
+      assert!(matches!(corrupted(at, value), Err(Error::Decode(_))), "{at} {value}");
+    }
+  }
+
+  #[test]
+  fn looping_channel_list_is_an_error() {
+    let mut file = export();
+    let first = {
+      let mut input = MdfInput { len: file.len() as u64, input: Cursor::new(&file) };
+      let hd = input.block(64).unwrap();
+      let dg = input.block(hd.link(0).unwrap()).unwrap();
+      let cg = input.block(dg.link(1).unwrap()).unwrap();
+      cg.link(CG_LINK_CN_FIRST).unwrap() as usize
+    };
+    // Point the first channel back at itself
+    let at = first + HEADER_SIZE as usize + 8 * CN_LINK_NEXT;
+    file[at..at + 8].copy_from_slice(&(first as u64).to_le_bytes());
+    assert!(matches!(read_mdf4(Cursor::new(file)), Err(Error::Decode(_))));
+  }
+
+  #[test]
+  fn damaged_bytes_never_panic() {
+    let file = export();
+    for at in 0..file.len() {
+      for value in [0x00, 0x7f, 0xff] {
+        let mut damaged = file.clone();
+        damaged[at] = value;
+        let _ = read_mdf4(Cursor::new(damaged));
+      }
+    }
+  }
+}
@@ -0,0 +1,219 @@
+use std::io::Write;
+use std::time::SystemTime;
+
+use crate::export::{channel_types, seconds_since, ExportOptions};
+use crate::interface::FeedValue;
+use crate::{ChannelType, Error, LogReader};
+
+// MegaLogViewer's binary log format, MLVLG version 2. All numbers are big
+// endian.
+const FORMAT_VERSION: u16 = 2;
+const HEADER_SIZE: usize = 24;
+const FIELD_SIZE: usize = 89;
+const NAME_SIZE: usize = 34;
+const UNITS_SIZE: usize = 10;
+const CATEGORY_SIZE: usize = 34;
+const MARKER_SIZE: usize = 50;
+
+const TYPE_U32: u8 = 4;
+const TYPE_F32: u8 = 7;
+const STYLE_FLOAT: u8 = 0;
+
+const BLOCK_DATA: u8 = 0;
+const BLOCK_MARKER: u8 = 1;
+
+// The log's own time channel counts milliseconds from the start, as the
+// block timestamps wrap after 655 ms
+const TIME_SCALE: f32 = 0.001;
+
+// Writes `text` into a fixed size, NUL padded field, cutting it short if
+// needed so there is always a terminator
+fn fixed(out: &mut Vec<u8>, text: &str, size: usize) {
+  let mut bytes = text.as_bytes()[..text.len().min(size - 1)].to_vec();
+  bytes.resize(size, 0);
+  out.extend_from_slice(&bytes);
+}
+
+struct Field<'a> {
+  name: &'a str,
+  units: &'a str,
+  field_type: u8,
+  scale: f32,
+  digits: i8,
+}
+
+impl Field<'_> {
+  fn write(&self, out: &mut Vec<u8>) {
+    out.push(self.field_type);
+    fixed(out, self.name, NAME_SIZE);
+    fixed(out, self.units, UNITS_SIZE);
+    out.push(STYLE_FLOAT);
+    out.extend_from_slice(&self.scale.to_be_bytes());
+    // Transform, added before scaling
+    out.extend_from_slice(&0f32.to_be_bytes());
+    out.push(self.digits as u8);
+    fixed(out, "", CATEGORY_SIZE);
+  }
+}
+
+struct Blocks<W> {
+  out: W,
+  counter: u8,
+  start: SystemTime,
+}
+
+impl<W: Write> Blocks<W> {
+  fn header(&mut self, kind: u8, time: SystemTime) -> Result<(), Error> {
+    let ticks = (seconds_since(self.start, time).max(0.0) * 100_000.0) as u64;
+    self.out.write_all(&[kind, self.counter])?;
+    self.out.write_all(&(ticks as u16).to_be_bytes())?;
+    self.counter = self.counter.wrapping_add(1);
+    Ok(())
+  }
+
+  fn data(&mut self, time: SystemTime, record: &[u8]) -> Result<(), Error> {
+    self.header(BLOCK_DATA, time)?;
+    self.out.write_all(record)?;
+    let crc = record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    self.out.write_all(&[crc])?;
+    Ok(())
+  }
+
+  fn marker(&mut self, time: SystemTime, message: &str) -> Result<(), Error> {
+    self.header(BLOCK_MARKER, time)?;
+    let mut bytes = vec![];
+    fixed(&mut bytes, message, MARKER_SIZE);
+    self.out.write_all(&bytes)?;
+    Ok(())
+  }
+}
+
+// Writes a MegaLogViewer log with a `Time` field in seconds from the start
+// of the log followed by the selected channels, and a marker where each
+// recording session begins. Missing integer values are written as 0.
+pub fn export_mlg<W: Write>(reader: &LogReader, options: &ExportOptions, out: W) -> Result<(), Error> {
+  let (schema, frames) = options.read(reader)?;
+  let types = channel_types(reader, &schema);
+  let start = reader.time_span()?.map_or(SystemTime::UNIX_EPOCH, |(first, _)| first);
+
+  let mut fields = vec![Field { name: "Time", units: "s", field_type: TYPE_U32, scale: TIME_SCALE, digits: 3 }];
+  for (key, channel_type) in schema.keys().iter().zip(&types) {
+    let (field_type, digits) = match channel_type {
+      ChannelType::Int => (TYPE_U32, 0),
+      ChannelType::Float => (TYPE_F32, 3),
+    };
+    let units = options.units.get(key).map_or("", String::as_str);
+    fields.push(Field { name: key, units, field_type, scale: 1.0, digits });
+  }
+  let field_count = u16::try_from(fields.len())
+    .map_err(|_| Error::Other("too many channels for an MLG log".to_string()))?;
+  let record_length = u16::try_from(fields.len() * 4)
+    .map_err(|_| Error::Other("too many channels for an MLG log".to_string()))?;
+  let data_start = (HEADER_SIZE + FIELD_SIZE * fields.len()) as u32;
+  let timestamp = seconds_since(SystemTime::UNIX_EPOCH, start).max(0.0) as u32;
+
+  let mut header = Vec::with_capacity(data_start as usize);
+  header.extend_from_slice(b"MLVLG\0");
+  header.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+  header.extend_from_slice(&timestamp.to_be_bytes());
+  // No info data
+  header.extend_from_slice(&0u32.to_be_bytes());
+  header.extend_from_slice(&data_start.to_be_bytes());
+  header.extend_from_slice(&record_length.to_be_bytes());
+  header.extend_from_slice(&field_count.to_be_bytes());
+  for field in &fields {
+    field.write(&mut header);
+  }
+
+  let mut out = std::io::BufWriter::new(out);
+  out.write_all(&header)?;
+  let mut blocks = Blocks { out, counter: 0, start };
+
+  let mut sessions = reader.sessions()?.into_iter().peekable();
+  let mut record = Vec::with_capacity(record_length as usize);
+  for frame in frames {
+    let frame = frame?;
+    // Only the latest session to have started matters if the export
+    // starts partway through the log
+    let mut started = None;
+    while let Some(session) = sessions.next_if(|s| s.start <= frame.time()) {
+      started = Some(session);
+    }
+    if let Some(session) = started {
+      let connection = session.info.connection.as_deref().unwrap_or("unknown connection");
+      let host = session.info.host.as_deref().unwrap_or("unknown host");
+      blocks.marker(frame.time(), &format!("Session {} ({connection} on {host})", session.id))?;
+    }
+
+    record.clear();
+    let millis = (seconds_since(start, frame.time()).max(0.0) * 1000.0).round() as u32;
+    record.extend_from_slice(&millis.to_be_bytes());
+    for (value, channel_type) in frame.values().iter().zip(&types) {
+      match (channel_type, value) {
+        (ChannelType::Int, FeedValue::Int(v)) => record.extend_from_slice(&v.to_be_bytes()),
+        (ChannelType::Int, FeedValue::Float(_)) => record.extend_from_slice(&0u32.to_be_bytes()),
+        (ChannelType::Float, FeedValue::Int(v)) => record.extend_from_slice(&(*v as f32).to_be_bytes()),
+        (ChannelType::Float, FeedValue::Float(v)) => record.extend_from_slice(&v.to_be_bytes()),
+      }
+    }
+    blocks.data(frame.time(), &record)?;
+  }
+  blocks.out.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{self, TempFile};
+  use crate::{LogFeedWriter, SessionInfo};
+
+  #[test]
+  fn header_and_record_layout() {
+    let log = testing::write_log(&["rpm", "map"], &[
+      (0, vec![FeedValue::Int(800), FeedValue::Float(30.5)]),
+      (20, vec![FeedValue::Int(900), FeedValue::Float(31.0)]),
+    ]);
+    let mut out = vec![];
+    export_mlg(&LogReader::open(log.path()).unwrap(), &ExportOptions::default(), &mut out).unwrap();
+
+    let u16_at = |at: usize| u16::from_be_bytes(out[at..at + 2].try_into().unwrap());
+    let u32_at = |at: usize| u32::from_be_bytes(out[at..at + 4].try_into().unwrap());
+    assert_eq!(&out[..6], b"MLVLG\0");
+    assert_eq!(u16_at(6), 2);
+    assert_eq!(u32_at(8), 1_700_000_000);
+    let data_start = u32_at(16) as usize;
+    assert_eq!(data_start, HEADER_SIZE + 3 * FIELD_SIZE);
+    let record_length = u16_at(20) as usize;
+    assert_eq!((record_length, u16_at(22)), (12, 3));
+    // Field names follow each field's type byte
+    assert_eq!(&out[HEADER_SIZE + FIELD_SIZE + 1..][..4], b"rpm\0");
+
+    // A block per point: type, counter, timestamp, the record and its
+    // checksum. The session was recorded after these made up points, so
+    // there's no marker.
+    let blocks = &out[data_start..];
+    assert_eq!(blocks.len(), 2 * (4 + record_length + 1));
+    let second = &blocks[4 + record_length + 1..];
+    assert_eq!((second[0], second[1]), (BLOCK_DATA, 1));
+    assert_eq!(u16::from_be_bytes([second[2], second[3]]), 2000);
+    assert_eq!(&second[4..8], &20u32.to_be_bytes());
+    assert_eq!(&second[8..12], &900u32.to_be_bytes());
+    assert_eq!(&second[12..16], &31f32.to_be_bytes());
+  }
+
+  #[test]
+  fn marks_session_starts() {
+    let file = TempFile::new("sq3");
+    let writer = LogFeedWriter::new(file.path(), vec!["rpm".to_string()], SessionInfo::new("sim")).unwrap();
+    writer.add(SystemTime::now() + std::time::Duration::from_secs(1), vec![FeedValue::Int(1)]).unwrap();
+    drop(writer);
+    let mut out = vec![];
+    export_mlg(&LogReader::open(file.path()).unwrap(), &ExportOptions::default(), &mut out).unwrap();
+
+    let marker = &out[HEADER_SIZE + 2 * FIELD_SIZE..][..4 + MARKER_SIZE];
+    assert_eq!((marker[0], marker[1]), (BLOCK_MARKER, 0));
+    assert!(marker[4..].starts_with(b"Session 1 (sim on "));
+    assert_eq!(out.len(), HEADER_SIZE + 2 * FIELD_SIZE + marker.len() + 4 + 8 + 1);
+  }
+}
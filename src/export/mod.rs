@@ -0,0 +1,203 @@
+mod csv;
+mod mdf4;
+mod mlg;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::{ChannelType, Error, FeedFrame, FeedSchema, LogFrames, LogReader};
+
+pub use csv::{csv_field, export_csv};
+pub use mdf4::{export_mdf4, read_mdf4, MdfChannel, MdfLog};
+pub use mlg::export_mlg;
+
+type Frames<'a> = Box<dyn Iterator<Item = Result<FeedFrame, Error>> + 'a>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimeFormat {
+  // Seconds since the first point in the log
+  #[default]
+  Relative,
+  // Seconds since the Unix epoch
+  Absolute,
+}
+
+// Selects what part of a log is exported and how
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+  // All channels if None
+  pub channels: Option<Vec<String>>,
+  pub start: Option<SystemTime>,
+  pub end: Option<SystemTime>,
+  pub time: TimeFormat,
+  // Rate in Hz to resample to, holding each channel's last value. Exports
+  // every recorded point if None.
+  pub resample: Option<f64>,
+  // Units by channel name, for formats that carry them. Logs don't record
+  // units, so channels not listed here have none.
+  pub units: HashMap<String, String>,
+}
+
+impl ExportOptions {
+  fn frames<'a>(&self, reader: &'a LogReader) -> Result<LogFrames<'a>, Error> {
+    let mut query = reader.query();
+    if let Some(channels) = &self.channels {
+      query = query.channels(channels);
+    }
+    if let Some(start) = self.start {
+      query = query.start(start);
+    }
+    if let Some(end) = self.end {
+      query = query.end(end);
+    }
+    query.frames()
+  }
+
+  // The frames to export, resampled if asked to be, and their schema
+  pub(crate) fn read<'a>(&self, reader: &'a LogReader)
+    -> Result<(Arc<FeedSchema>, Frames<'a>), Error> {
+    let frames = self.frames(reader)?;
+    let schema = frames.schema().clone();
+    Ok((schema, match self.resample {
+      Some(rate) => Box::new(Resample::new(frames, resample_period(rate)?)),
+      None => Box::new(frames),
+    }))
+  }
+
+  // Where exported time starts counting from
+  pub(crate) fn epoch(&self, reader: &LogReader) -> Result<SystemTime, Error> {
+    Ok(match self.time {
+      TimeFormat::Relative => reader.time_span()?.map(|(first, _)| first).unwrap_or(SystemTime::UNIX_EPOCH),
+      TimeFormat::Absolute => SystemTime::UNIX_EPOCH,
+    })
+  }
+}
+
+// The stored type of each channel in `schema`
+pub(crate) fn channel_types(reader: &LogReader, schema: &FeedSchema) -> Vec<ChannelType> {
+  schema.keys().iter()
+    .map(|key| reader.channels().iter()
+      .find(|c| &c.name == key)
+      .map_or(ChannelType::Float, |c| c.channel_type))
+    .collect()
+}
+
+// Seconds from `epoch` to `time`, negative if `time` is earlier
+pub(crate) fn seconds_since(epoch: SystemTime, time: SystemTime) -> f64 {
+  match time.duration_since(epoch) {
+    Ok(d) => d.as_secs_f64(),
+    Err(e) => -e.duration().as_secs_f64(),
+  }
+}
+
+// Time between resampled frames, which can't be finer than the
+// nanoseconds logs are recorded in
+fn resample_period(rate: f64) -> Result<Duration, Error> {
+  if !(rate.is_finite() && rate > 0.0 && 1.0 / rate >= 1e-9) {
+    return Err(Error::Other(format!("invalid resample rate {rate}, expected up to 1GHz")));
+  }
+  Duration::try_from_secs_f64(1.0 / rate)
+    .map_err(|_| Error::Other(format!("resample rate {rate} is too low")))
+}
+
+// Turns irregular frames into one every 1/rate seconds from the first,
+// each carrying the values of the latest frame at or before its time
+struct Resample<I> {
+  frames: I,
+  period: Duration,
+  first: Option<SystemTime>,
+  count: u32,
+  current: Option<FeedFrame>,
+  next: Option<FeedFrame>,
+  done: bool,
+}
+
+impl<I: Iterator<Item = Result<FeedFrame, Error>>> Resample<I> {
+  fn new(frames: I, period: Duration) -> Resample<I> {
+    Resample {
+      frames,
+      period,
+      first: None,
+      count: 0,
+      current: None,
+      next: None,
+      done: false,
+    }
+  }
+
+  fn fetch(&mut self) -> Result<(), Error> {
+    if self.next.is_none() && !self.done {
+      match self.frames.next().transpose()? {
+        Some(frame) => self.next = Some(frame),
+        None => self.done = true,
+      }
+    }
+    Ok(())
+  }
+
+  fn resample_next(&mut self) -> Result<Option<FeedFrame>, Error> {
+    self.fetch()?;
+    let first = match self.first {
+      Some(first) => first,
+      None => match &self.next {
+        Some(frame) => *self.first.insert(frame.time()),
+        None => return Ok(None),
+      },
+    };
+    // A sample time past what SystemTime holds is past the last frame too
+    let Some(due) = self.period.checked_mul(self.count).and_then(|offset| first.checked_add(offset))
+      else { return Ok(None) };
+    // Catch up to the latest frame at or before this sample
+    while let Some(frame) = self.next.take_if(|f| f.time() <= due) {
+      self.current = Some(frame);
+      self.fetch()?;
+    }
+    if self.next.is_none() && self.current.as_ref().is_none_or(|c| c.time() < due) {
+      // Past the last frame
+      return Ok(None);
+    }
+    self.count += 1;
+    let Some(current) = &self.current else { return Ok(None) };
+    Ok(Some(FeedFrame::new(due, current.schema().clone(), current.values().to_vec())?))
+  }
+}
+
+impl<I: Iterator<Item = Result<FeedFrame, Error>>> Iterator for Resample<I> {
+  type Item = Result<FeedFrame, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.resample_next().transpose()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interface::FeedValue;
+
+  fn frames(times_ms: &[u64]) -> Vec<Result<FeedFrame, Error>> {
+    let schema = Arc::new(FeedSchema::new(vec!["n".to_string()]));
+    times_ms.iter().enumerate()
+      .map(|(i, &ms)| FeedFrame::new(SystemTime::UNIX_EPOCH + Duration::from_millis(ms), schema.clone(),
+                                     vec![FeedValue::Int(i as u32)]))
+      .collect()
+  }
+
+  #[test]
+  fn resample_holds_the_latest_value() {
+    let resampled = Resample::new(frames(&[0, 25, 130, 300]).into_iter(), resample_period(10.0).unwrap())
+      .map(|f| f.unwrap())
+      .map(|f| (f.time().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis(), f.get_f64("n").unwrap()))
+      .collect::<Vec<_>>();
+    assert_eq!(resampled, [(0, 0.0), (100, 1.0), (200, 2.0), (300, 3.0)]);
+  }
+
+  #[test]
+  fn resample_rate_must_be_usable() {
+    for rate in [0.0, -1.0, f64::NAN, f64::INFINITY, 2e9, 1e-300] {
+      assert!(resample_period(rate).is_err(), "{rate}");
+    }
+    assert_eq!(resample_period(1e9).unwrap(), Duration::from_nanos(1));
+  }
+}
@@ -0,0 +1,377 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::interface;
+use crate::Error;
+
+static NEXT_SCHEMA_ID: AtomicU64 = AtomicU64::new(1);
+
+// The channel names from one `Description`, shared by every frame decoded
+// with it
+#[derive(Debug)]
+pub struct FeedSchema {
+  id: u64,
+  keys: Vec<String>,
+  index: HashMap<String, usize>,
+}
+
+// Position of a channel within a particular schema. Looking it up once and
+// reusing it avoids hashing the name for every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeedIndex {
+  schema: u64,
+  index: usize,
+}
+
+impl FeedSchema {
+  pub fn new(keys: Vec<String>) -> FeedSchema {
+    let index = keys.iter().enumerate()
+      .map(|(i, key)| (key.clone(), i))
+      .collect();
+    FeedSchema {
+      id: NEXT_SCHEMA_ID.fetch_add(1, Ordering::Relaxed),
+      keys,
+      index,
+    }
+  }
+
+  pub fn keys(&self) -> &[String] {
+    &self.keys
+  }
+
+  pub fn len(&self) -> usize {
+    self.keys.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.keys.is_empty()
+  }
+
+  pub fn index_of(&self, key: &str) -> Option<FeedIndex> {
+    self.index.get(key).map(|&index| FeedIndex { schema: self.id, index })
+  }
+}
+
+fn as_f64(value: &interface::FeedValue) -> f64 {
+  match value {
+    interface::FeedValue::Int(v) => *v as f64,
+    interface::FeedValue::Float(v) => *v as f64,
+  }
+}
+
+// One feed message along with the schema it was decoded with
+#[derive(Debug, Clone)]
+pub struct FeedFrame {
+  time: SystemTime,
+  schema: Arc<FeedSchema>,
+  values: Vec<interface::FeedValue>,
+}
+
+impl FeedFrame {
+  pub fn new(time: SystemTime, schema: Arc<FeedSchema>, values: Vec<interface::FeedValue>)
+    -> Result<FeedFrame, Error> {
+    if values.len() != schema.len() {
+      return Err(Error::FeedLength { expected: schema.len(), received: values.len() });
+    }
+    Ok(FeedFrame { time, schema, values })
+  }
+
+  pub fn time(&self) -> SystemTime {
+    self.time
+  }
+
+  pub fn schema(&self) -> &Arc<FeedSchema> {
+    &self.schema
+  }
+
+  pub fn keys(&self) -> &[String] {
+    self.schema.keys()
+  }
+
+  pub fn values(&self) -> &[interface::FeedValue] {
+    &self.values
+  }
+
+  pub fn into_values(self) -> Vec<interface::FeedValue> {
+    self.values
+  }
+
+  pub fn get(&self, key: &str) -> Option<&interface::FeedValue> {
+    self.schema.index.get(key).map(|&i| &self.values[i])
+  }
+
+  pub fn get_f64(&self, key: &str) -> Option<f64> {
+    self.get(key).map(as_f64)
+  }
+
+  // None if `index` was looked up in a different schema
+  pub fn at(&self, index: FeedIndex) -> Option<&interface::FeedValue> {
+    if index.schema != self.schema.id {
+      return None;
+    }
+    self.values.get(index.index)
+  }
+
+  pub fn at_f64(&self, index: FeedIndex) -> Option<f64> {
+    self.at(index).map(as_f64)
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &interface::FeedValue)> {
+    self.schema.keys.iter().map(String::as_str).zip(self.values.iter())
+  }
+}
+
+// What a subscription does with a new frame when its queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+  // Discard the oldest queued frame to make room
+  DropOldest,
+  // Discard the new frame
+  DropNewest,
+  // Wait for the subscriber to catch up. This holds up the receive thread,
+  // and with it every other subscriber and command responses.
+  Block,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SubscribeOptions {
+  pub capacity: usize,
+  pub overflow: Overflow,
+}
+
+impl Default for SubscribeOptions {
+  fn default() -> Self {
+    SubscribeOptions { capacity: 1024, overflow: Overflow::DropOldest }
+  }
+}
+
+struct Queue {
+  frames: VecDeque<FeedFrame>,
+  dropped: u64,
+  closed: bool,
+}
+
+// The half of a subscription kept by the Manager
+pub(crate) struct Subscriber {
+  queue: Mutex<Queue>,
+  changed: Condvar,
+  options: SubscribeOptions,
+}
+
+impl Subscriber {
+  pub fn push(&self, frame: FeedFrame) {
+    let mut queue = self.queue.lock().unwrap();
+    while !queue.closed && queue.frames.len() >= self.options.capacity {
+      match self.options.overflow {
+        Overflow::DropOldest => {
+          queue.frames.pop_front();
+          queue.dropped += 1;
+        },
+        Overflow::DropNewest => {
+          queue.dropped += 1;
+          return;
+        },
+        Overflow::Block => queue = self.changed.wait(queue).unwrap(),
+      }
+    }
+    if queue.closed {
+      return;
+    }
+    queue.frames.push_back(frame);
+    self.changed.notify_all();
+  }
+
+  pub fn close(&self) {
+    self.queue.lock().unwrap().closed = true;
+    self.changed.notify_all();
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.queue.lock().unwrap().closed
+  }
+}
+
+// Receives feed frames from `Manager::subscribe`. Dropping it unsubscribes.
+// Once the Manager shuts down, the frames already queued can still be
+// received, after which `recv` returns None.
+pub struct FeedSubscription {
+  shared: Arc<Subscriber>,
+}
+
+impl FeedSubscription {
+  pub(crate) fn new(options: SubscribeOptions) -> (FeedSubscription, Arc<Subscriber>) {
+    let shared = Arc::new(Subscriber {
+      queue: Mutex::new(Queue { frames: VecDeque::new(), dropped: 0, closed: false }),
+      changed: Condvar::new(),
+      options: SubscribeOptions { capacity: options.capacity.max(1), ..options },
+    });
+    (FeedSubscription { shared: shared.clone() }, shared)
+  }
+
+  pub fn recv(&self) -> Option<FeedFrame> {
+    let mut queue = self.shared.queue.lock().unwrap();
+    loop {
+      if let Some(frame) = queue.frames.pop_front() {
+        self.shared.changed.notify_all();
+        return Some(frame);
+      }
+      if queue.closed {
+        return None;
+      }
+      queue = self.shared.changed.wait(queue).unwrap();
+    }
+  }
+
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<FeedFrame, mpsc::RecvTimeoutError> {
+    let deadline = Instant::now() + timeout;
+    let mut queue = self.shared.queue.lock().unwrap();
+    loop {
+      if let Some(frame) = queue.frames.pop_front() {
+        self.shared.changed.notify_all();
+        return Ok(frame);
+      }
+      if queue.closed {
+        return Err(mpsc::RecvTimeoutError::Disconnected);
+      }
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(mpsc::RecvTimeoutError::Timeout);
+      }
+      queue = self.shared.changed.wait_timeout(queue, deadline - now).unwrap().0;
+    }
+  }
+
+  pub fn try_recv(&self) -> Option<FeedFrame> {
+    let frame = self.shared.queue.lock().unwrap().frames.pop_front();
+    if frame.is_some() {
+      self.shared.changed.notify_all();
+    }
+    frame
+  }
+
+  // Blocks for each frame until the Manager shuts down
+  pub fn iter(&self) -> impl Iterator<Item = FeedFrame> + '_ {
+    std::iter::from_fn(move || self.recv())
+  }
+
+  // Frames discarded because the queue was full
+  pub fn dropped(&self) -> u64 {
+    self.shared.queue.lock().unwrap().dropped
+  }
+
+  pub fn len(&self) -> usize {
+    self.shared.queue.lock().unwrap().frames.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl Drop for FeedSubscription {
+  fn drop(&mut self) {
+    self.shared.close();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use interface::FeedValue;
+
+  fn frame(value: u32) -> FeedFrame {
+    let schema = Arc::new(FeedSchema::new(vec!["n".to_string()]));
+    FeedFrame::new(SystemTime::now(), schema, vec![FeedValue::Int(value)]).unwrap()
+  }
+
+  fn subscription(capacity: usize, overflow: Overflow) -> (FeedSubscription, Arc<Subscriber>) {
+    FeedSubscription::new(SubscribeOptions { capacity, overflow })
+  }
+
+  fn drain(feed: &FeedSubscription) -> Vec<f64> {
+    std::iter::from_fn(|| feed.try_recv()).map(|f| f.get_f64("n").unwrap()).collect()
+  }
+
+  #[test]
+  fn drop_oldest_keeps_the_latest() {
+    let (feed, subscriber) = subscription(2, Overflow::DropOldest);
+    for n in 1..=4 {
+      subscriber.push(frame(n));
+    }
+    assert_eq!(drain(&feed), [3.0, 4.0]);
+    assert_eq!(feed.dropped(), 2);
+  }
+
+  #[test]
+  fn drop_newest_keeps_the_earliest() {
+    let (feed, subscriber) = subscription(2, Overflow::DropNewest);
+    for n in 1..=4 {
+      subscriber.push(frame(n));
+    }
+    assert_eq!(drain(&feed), [1.0, 2.0]);
+    assert_eq!(feed.dropped(), 2);
+  }
+
+  #[test]
+  fn block_waits_for_the_subscriber() {
+    let (feed, subscriber) = subscription(1, Overflow::Block);
+    let pusher = std::thread::spawn(move || for n in 1..=3 {
+      subscriber.push(frame(n));
+    });
+    let received = (0..3).map(|_| feed.recv_timeout(Duration::from_secs(2)).unwrap().get_f64("n").unwrap())
+      .collect::<Vec<_>>();
+    pusher.join().unwrap();
+    assert_eq!(received, [1.0, 2.0, 3.0]);
+    assert_eq!(feed.dropped(), 0);
+  }
+
+  #[test]
+  fn queued_frames_outlive_close() {
+    let (feed, subscriber) = subscription(4, Overflow::DropOldest);
+    subscriber.push(frame(1));
+    subscriber.close();
+    subscriber.push(frame(2));
+    assert_eq!(feed.recv().map(|f| f.get_f64("n")), Some(Some(1.0)));
+    assert!(feed.recv().is_none());
+    assert!(matches!(feed.recv_timeout(Duration::ZERO), Err(mpsc::RecvTimeoutError::Disconnected)));
+  }
+
+  #[test]
+  fn dropping_the_subscription_closes_it() {
+    let (feed, subscriber) = subscription(4, Overflow::Block);
+    drop(feed);
+    assert!(subscriber.is_closed());
+  }
+
+  #[test]
+  fn looks_up_values_by_name_and_index() {
+    let schema = Arc::new(FeedSchema::new(vec!["rpm".to_string(), "map".to_string()]));
+    let frame = FeedFrame::new(SystemTime::now(), schema.clone(),
+                               vec![FeedValue::Int(800), FeedValue::Float(35.5)]).unwrap();
+    assert_eq!(frame.get("rpm"), Some(&FeedValue::Int(800)));
+    assert_eq!(frame.get_f64("map"), Some(35.5));
+    assert_eq!(frame.get("tps"), None);
+
+    let map = schema.index_of("map").unwrap();
+    assert_eq!(frame.at_f64(map), Some(35.5));
+    assert!(schema.index_of("tps").is_none());
+    assert_eq!(frame.iter().map(|(k, _)| k).collect::<Vec<_>>(), ["rpm", "map"]);
+  }
+
+  #[test]
+  fn index_from_another_schema_is_rejected() {
+    let keys = vec!["rpm".to_string()];
+    let old = FeedSchema::new(keys.clone());
+    let frame = FeedFrame::new(SystemTime::now(), Arc::new(FeedSchema::new(keys)), vec![FeedValue::Int(1)]).unwrap();
+    assert_eq!(frame.at(old.index_of("rpm").unwrap()), None);
+  }
+
+  #[test]
+  fn frame_length_must_match_schema() {
+    let schema = Arc::new(FeedSchema::new(vec!["rpm".to_string(), "map".to_string()]));
+    let result = FeedFrame::new(SystemTime::now(), schema, vec![FeedValue::Int(1)]);
+    assert!(matches!(result, Err(Error::FeedLength { expected: 2, received: 1 })));
+  }
+}
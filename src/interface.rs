@@ -1,5 +1,9 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::Error;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "method")]
@@ -8,6 +12,27 @@ pub enum RequestMessage {
   Ping { id: u32 },
   Structure { id: u32 },
   Get { id: u32, path: StructurePath },
+  Set { id: u32, path: StructurePath, value: ResponseValue },
+}
+
+impl RequestMessage {
+  pub fn id(&self) -> u32 {
+    match self {
+      RequestMessage::Ping { id } |
+      RequestMessage::Structure { id } |
+      RequestMessage::Get { id, .. } |
+      RequestMessage::Set { id, .. } => *id,
+    }
+  }
+
+  pub fn set_id(&mut self, new_id: u32) {
+    match self {
+      RequestMessage::Ping { id } |
+      RequestMessage::Structure { id } |
+      RequestMessage::Get { id, .. } |
+      RequestMessage::Set { id, .. } => *id = new_id,
+    }
+  }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -17,7 +42,7 @@ pub enum StructurePathElement {
   MapField(String),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct StructurePath(Vec<StructurePathElement>);
 
 impl StructurePath {
@@ -30,23 +55,81 @@ impl StructurePath {
     self.0.push(StructurePathElement::ArrayIndex(u));
     self
   }
+  pub fn elements(&self) -> &[StructurePathElement] {
+    &self.0
+  }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Written as map fields separated by dots and array indices in brackets,
+// e.g. `outputs[3].angle`
+impl fmt::Display for StructurePath {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for (i, element) in self.0.iter().enumerate() {
+      match element {
+        StructurePathElement::ArrayIndex(index) => write!(f, "[{index}]")?,
+        StructurePathElement::MapField(field) if i == 0 => write!(f, "{field}")?,
+        StructurePathElement::MapField(field) => write!(f, ".{field}")?,
+      }
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for StructurePath {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<StructurePath, Error> {
+    let invalid = || Error::Other(format!("invalid path {s}"));
+    let mut path = StructurePath::new();
+    let mut rest = s;
+    while !rest.is_empty() {
+      if let Some(after) = rest.strip_prefix('[') {
+        let (index, after) = after.split_once(']').ok_or_else(invalid)?;
+        path = path.add_index(index.trim().parse().map_err(|_| invalid())?);
+        rest = after;
+      } else {
+        // Fields after the first are introduced by a dot
+        if !path.0.is_empty() {
+          rest = rest.strip_prefix('.').ok_or_else(invalid)?;
+        }
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        if end == 0 {
+          return Err(invalid());
+        }
+        path = path.add_str(&rest[..end]);
+        rest = &rest[end..];
+      }
+    }
+    Ok(path)
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct StructureLeaf {
-  _type: String,
-  description: String,
+#[serde(rename = "_type")]
+  pub leaf_type: String,
+  pub description: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Only a map with nothing but an `error` key is an error; any other map that
+// happens to have one is still a `ResponseValue::Map`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ResponseError {
+  pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum ResponseValue {
   Str(String),
-  Float(f32),
+  // Ahead of Float so that whole numbers keep their type
   Int(u32),
+  Float(f32),
   Bool(bool),
   Output(OutputValue),
   Array(Vec<ResponseValue>),
+  Error(ResponseError),
   Leaf(StructureLeaf),
   Map(HashMap<String, ResponseValue>),
   None
@@ -62,14 +145,14 @@ pub enum Message {
   Response{ id: u32, response: ResponseValue },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum FeedValue {
   Int(u32),
   Float(f32),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum OutputType {
   Ignition,
@@ -77,7 +160,7 @@ pub enum OutputType {
   Disabled,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct OutputValue {
   pin: u32,
 #[serde(rename = "type")]
@@ -85,3 +168,64 @@ pub struct OutputValue {
   inverted: bool,
   angle: f32,
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode(value: serde_json::Value) -> ResponseValue {
+    let bytes = serde_cbor::to_vec(&value).unwrap();
+    serde_cbor::from_slice(&bytes).unwrap()
+  }
+
+  #[test]
+  fn error_response() {
+    let value = decode(serde_json::json!({"error": "invalid path"}));
+    assert_eq!(value, ResponseValue::Error(ResponseError { error: "invalid path".to_string() }));
+  }
+
+  #[test]
+  fn map_with_error_key_is_a_map() {
+    let value = decode(serde_json::json!({"error": "none", "count": 3}));
+    let ResponseValue::Map(fields) = value else { panic!("decoded as {value:?}") };
+    assert_eq!(fields["error"], ResponseValue::Str("none".to_string()));
+    assert_eq!(fields["count"], ResponseValue::Int(3));
+  }
+
+  #[test]
+  fn set_request_round_trip() {
+    let request = RequestMessage::Set {
+      id: 7,
+      path: StructurePath::new().add_str("outputs").add_index(3).add_str("angle"),
+      value: ResponseValue::Float(12.5),
+    };
+    let bytes = serde_cbor::to_vec(&Message::Request(request)).unwrap();
+    let Message::Request(RequestMessage::Set { id, path, value }) = serde_cbor::from_slice(&bytes).unwrap() else {
+      panic!("not a set request");
+    };
+    assert_eq!(id, 7);
+    assert_eq!(path.to_string(), "outputs[3].angle");
+    assert_eq!(value, ResponseValue::Float(12.5));
+  }
+
+  #[test]
+  fn structure_path_round_trip() {
+    for text in ["decoder", "decoder.offset", "outputs[3].angle", "tables[0][1]", "[2].pin"] {
+      let path: StructurePath = text.parse().unwrap();
+      assert_eq!(path.to_string(), text);
+    }
+    let path: StructurePath = "outputs[ 3 ].angle".parse().unwrap();
+    assert!(matches!(path.elements(), [
+      StructurePathElement::MapField(outputs),
+      StructurePathElement::ArrayIndex(3),
+      StructurePathElement::MapField(angle),
+    ] if outputs == "outputs" && angle == "angle"));
+  }
+
+  #[test]
+  fn invalid_structure_paths() {
+    for text in ["a..b", "a.", ".a", "a[", "a[x]", "a[-1]", "a[1]b"] {
+      assert!(text.parse::<StructurePath>().is_err(), "{text}");
+    }
+  }
+}
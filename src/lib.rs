@@ -1,28 +1,146 @@
 pub mod interface;
 pub mod connection;
+mod config;
+mod error;
+pub mod export;
+mod feed;
 mod log;
+mod log_reader;
+mod request;
+mod status;
+#[cfg(test)]
+mod testing;
 
-pub use log::LogFeedWriter;
+pub use config::{sorted_fields, ConfigChange, ConfigComparison, ConfigSnapshot};
+pub use error::{CommandError, Error};
+pub use feed::{FeedFrame, FeedIndex, FeedSchema, FeedSubscription, Overflow, SubscribeOptions};
+pub use log::{ChannelType, LogChannel, LogFeedWriter, SessionInfo};
+pub use log_reader::{LogFrames, LogQuery, LogReader, LogSession};
+pub use request::ResponseFuture;
+pub use status::{Stats, Status};
 
 use std::thread;
-use std::sync::{Mutex, Arc};
-use std::time::{Duration, SystemTime};
-use std::collections::VecDeque;
+use std::sync::{mpsc, Mutex, Arc};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
 
-type FeedCallback = dyn FnMut(SystemTime, &Vec<String>, &Vec<interface::FeedValue>) -> () + Send; 
-type RequestCallback = dyn FnOnce(interface::ResponseValue) -> () + Send; 
+type RequestCallback = dyn FnOnce(Result<interface::ResponseValue, CommandError>) + Send;
+type StatusCallback = dyn FnMut(&Status) + Send;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CommandOptions {
+  // How long to wait for a response before resending
+  pub timeout: Duration,
+  // Number of resends before the command fails with CommandError::Timeout
+  pub retries: u32,
+}
+
+impl Default for CommandOptions {
+  fn default() -> Self {
+    CommandOptions { timeout: Duration::from_millis(500), retries: 2 }
+  }
+}
+
+// The firmware services one request at a time, so only this many commands
+// are on the wire at once; the rest wait in `pending`.
+const MAX_IN_FLIGHT: usize = 1;
 
 struct Command {
+  id: u32,
   callback: Box<RequestCallback>,
-  message: interface::Message,
+  message: interface::RequestMessage,
+  options: CommandOptions,
+  attempts: u32,
+  sent_at: Instant,
 }
 
 struct ConnectionState {
-  on_feed: Option<Box<FeedCallback>>,
-  commands: VecDeque<Command>,
+  subscribers: Vec<Arc<feed::Subscriber>>,
+  on_status: Option<Box<StatusCallback>>,
+  // Reported but not yet passed to `on_status`
+  statuses: Vec<Status>,
+  pending: VecDeque<Command>,
+  in_flight: HashMap<u32, Command>,
+  next_id: u32,
+  default_options: CommandOptions,
+  stats: Stats,
+  link: Option<connection::LinkStatus>,
   running: bool,
 }
 
+impl ConnectionState {
+  // Without a callback statuses are only counted in `stats`. The callback
+  // runs later, from `Manager::deliver_statuses`.
+  fn report(&mut self, status: Status) {
+    if self.on_status.is_some() {
+      self.statuses.push(status);
+    }
+  }
+
+  fn set_link(&mut self, status: connection::LinkStatus) {
+    if self.link == Some(status) {
+      return;
+    }
+    if status == connection::LinkStatus::Connected && self.link.is_some() {
+      self.stats.reconnects += 1;
+    }
+    self.link = Some(status);
+    self.report(Status::Link(status));
+  }
+
+  fn dispatch(&mut self, writer: &connection::Writer) {
+    while self.in_flight.len() < MAX_IN_FLIGHT {
+      let Some(mut command) = self.pending.pop_front() else { break };
+      if writer.send(interface::Message::Request(command.message.clone())).is_err() {
+        (command.callback)(Err(CommandError::Disconnected));
+        continue;
+      }
+      command.attempts = 1;
+      command.sent_at = Instant::now();
+      self.stats.commands_sent += 1;
+      self.in_flight.insert(command.id, command);
+    }
+  }
+
+  // Resends commands whose response is overdue, and fails the ones that
+  // have run out of retries. Resends reuse the original id, so a late reply
+  // to an earlier attempt still completes the command.
+  fn expire(&mut self, writer: &connection::Writer) {
+    let now = Instant::now();
+    let overdue : Vec<u32> = self.in_flight.values()
+      .filter(|c| now.duration_since(c.sent_at) >= c.options.timeout)
+      .map(|c| c.id)
+      .collect();
+
+    for id in overdue {
+      let command = self.in_flight.get_mut(&id).unwrap();
+      if command.attempts <= command.options.retries {
+        // If this fails the command simply runs out of retries
+        let _ = writer.send(interface::Message::Request(command.message.clone()));
+        command.attempts += 1;
+        command.sent_at = now;
+        self.stats.command_retries += 1;
+      } else {
+        let command = self.in_flight.remove(&id).unwrap();
+        let attempts = command.attempts;
+        self.stats.command_timeouts += 1;
+        self.report(Status::CommandTimeout { id, attempts });
+        (command.callback)(Err(CommandError::Timeout { id, attempts }));
+      }
+    }
+    self.dispatch(writer);
+  }
+
+  fn fail_all(&mut self) {
+    let commands = self.in_flight.drain().map(|(_, c)| c)
+      .chain(self.pending.drain(..))
+      .collect::<Vec<Command>>();
+    for command in commands {
+      (command.callback)(Err(CommandError::Disconnected));
+    }
+  }
+}
+
 pub struct Manager{
   thread: Option<thread::JoinHandle<()>>,
   state: Arc<Mutex<ConnectionState>>,
@@ -32,8 +150,15 @@ pub struct Manager{
 impl Manager {
   pub fn new(connection: Box<dyn connection::Connection + Send>) -> Manager {
     let state = Arc::new(Mutex::new(ConnectionState{
-      on_feed: None,
-      commands: VecDeque::new(),
+      subscribers: Vec::new(),
+      on_status: None,
+      statuses: Vec::new(),
+      pending: VecDeque::new(),
+      in_flight: HashMap::new(),
+      next_id: 1,
+      default_options: CommandOptions::default(),
+      stats: Stats::default(),
+      link: None,
       running: true,
       }));
 
@@ -49,31 +174,87 @@ impl Manager {
   }
 
   fn main_loop(conn: Box<dyn connection::Connection>, state: Arc<Mutex<ConnectionState>>) {
-    let mut current_keys : Option<Vec<String>> = None;
+    let mut current_schema : Option<Arc<FeedSchema>> = None;
+    // Kept across link changes, so that only a different layout counts as
+    // a description change
+    let mut last_schema : Option<Arc<FeedSchema>> = None;
+    let writer = conn.get_writer();
     loop {
       match conn.recv(Duration::from_millis(100)) {
-        Ok(connection::RxMessage{time, payload}) => {
+        Ok(connection::RxEvent::Error(e)) => {
+          let mut state = state.lock().unwrap();
+          if let Error::Decode(_) = e {
+            state.stats.decode_errors += 1;
+            state.report(Status::DecodeError(e));
+          } else {
+            state.stats.errors += 1;
+            state.report(Status::Error(e));
+          }
+        },
+        Ok(connection::RxEvent::Link(status)) => {
+          if status != connection::LinkStatus::Connected {
+            // The device may come back with different firmware, so wait for
+            // a fresh description before decoding feeds again
+            current_schema = None;
+          }
+          state.lock().unwrap().set_link(status);
+        },
+        Ok(connection::RxEvent::Message(connection::RxMessage{time, payload})) => {
+          let mut state = state.lock().unwrap();
+          state.stats.last_message = Some(time);
+          if state.link.is_none() {
+            state.set_link(connection::LinkStatus::Connected);
+          }
           match payload {
             interface::Message::Feed{values} => {
-              let mut state = state.lock().unwrap();
-              if let Some(keys) = &current_keys {
-                if let Some(cb) = &mut state.on_feed {
-                  cb(time, &keys, &values);
-                }
+              state.stats.feeds += 1;
+              let frame = match &current_schema {
+                Some(schema) => FeedFrame::new(time, schema.clone(), values),
+                None => Err(Error::Other("feed received before a description".to_string())),
+              };
+              match frame {
+                Ok(frame) => {
+                  state.subscribers.retain(|s| !s.is_closed());
+                  let subscribers = state.subscribers.clone();
+                  // Subscribers may block, which mustn't hold up commands
+                  drop(state);
+                  for subscriber in &subscribers {
+                    subscriber.push(frame.clone());
+                  }
+                },
+                Err(e) => {
+                  state.stats.dropped_feeds += 1;
+                  state.report(Status::FeedDropped(e));
+                },
               }
             },
               interface::Message::Description{keys} => {
-                current_keys = Some(keys)
+                state.stats.descriptions += 1;
+                let schema = match &last_schema {
+                  Some(last) if last.keys() == &keys[..] => last.clone(),
+                  _ => {
+                    let schema = Arc::new(FeedSchema::new(keys));
+                    state.stats.description_changes += 1;
+                    state.report(Status::DescriptionChanged(schema.clone()));
+                    last_schema = Some(schema.clone());
+                    schema
+                  },
+                };
+                current_schema = Some(schema)
               },
-              interface::Message::Response { id: _, response } => {
-                let mut state = state.lock().unwrap();
-                if let Some(command) = state.commands.pop_front() {
-                  (command.callback)(response);
-                  if let Some(command) = &state.commands.front() {
-                    let msg = command.message.clone();
-                    conn.get_writer().send(msg);
-                  }
+              interface::Message::Response { id, response } => {
+                match state.in_flight.remove(&id) {
+                  Some(command) => match response {
+                    interface::ResponseValue::Error(e) =>
+                      (command.callback)(Err(CommandError::Rejected(e.error))),
+                    response => (command.callback)(Ok(response)),
+                  },
+                  None => {
+                    state.stats.unmatched_responses += 1;
+                    state.report(Status::UnmatchedResponse { id });
+                  },
                 }
+                state.dispatch(&writer);
               },
               _ => (),
           }
@@ -83,31 +264,156 @@ impl Manager {
           _ => break,
       }
       // Exit condition
-      let state = state.lock().unwrap();
-      if !state.running {
+      let running = {
+        let mut state = state.lock().unwrap();
+        state.expire(&writer);
+        state.running
+      };
+      Self::deliver_statuses(&state);
+      if !running {
         break;
       }
 
     }
+    {
+      let mut state = state.lock().unwrap();
+      state.running = false;
+      state.set_link(connection::LinkStatus::Disconnected);
+      state.fail_all();
+      for subscriber in state.subscribers.drain(..) {
+        subscriber.close();
+      }
+    }
+    Self::deliver_statuses(&state);
+  }
+
+  // Passes what was reported to the status callback with the state
+  // unlocked, so the callback can use the Manager
+  fn deliver_statuses(state: &Mutex<ConnectionState>) {
+    let (statuses, mut callback) = {
+      let mut state = state.lock().unwrap();
+      if state.statuses.is_empty() {
+        return;
+      }
+      (std::mem::take(&mut state.statuses), state.on_status.take())
+    };
+    if let Some(cb) = &mut callback {
+      for status in &statuses {
+        cb(status);
+      }
+    }
+    // Unless the callback was replaced meanwhile
+    let mut state = state.lock().unwrap();
+    if state.on_status.is_none() {
+      state.on_status = callback;
+    }
+  }
+
+  // Adds a feed subscriber with its own queue. Any number can be active.
+  pub fn subscribe(&self, options: SubscribeOptions) -> FeedSubscription {
+    let (subscription, subscriber) = FeedSubscription::new(options);
+    let mut locked = self.state.lock().unwrap();
+    if locked.running {
+      locked.subscribers.push(subscriber);
+    } else {
+      subscriber.close();
+    }
+    subscription
+  }
+
+  // Options used by `command` and `set`
+  pub fn set_command_options(&self, options: CommandOptions) {
+    self.state.lock().unwrap().default_options = options;
   }
 
-  pub fn on_feed<F>(&self, f: F)
-  where F: FnMut(SystemTime, &Vec<String>, &Vec<interface::FeedValue>) -> () + Send + 'static {
+  // Called on link changes, new feed layouts, and errors the connection
+  // recovered from, e.g. a malformed packet or a failed write. Without a
+  // callback they're only counted in `stats`. Runs on the thread that
+  // delivers responses, so like a command callback it must not call
+  // `request_blocking`; other Manager methods are fine.
+  pub fn on_status<F>(&self, f: F)
+  where F: FnMut(&Status) + Send + 'static {
     let mut locked = self.state.lock().unwrap();
-    locked.on_feed = Some(Box::new(f));
+    locked.on_status = Some(Box::new(f));
+  }
+
+  // Queues a request, replacing its id with one unique to this Manager.
+  // Returns the id the request was sent with.
+  pub fn command<F>(&self, msg: interface::RequestMessage, callback: F) -> u32
+  where F: FnOnce(Result<interface::ResponseValue, CommandError>) + 'static + Send {
+    let options = self.state.lock().unwrap().default_options;
+    self.command_with_options(msg, options, callback)
   }
 
-  pub fn command<F>(&self, msg: interface::Message, callback: F)
-  where F: FnOnce(interface::ResponseValue) -> () + 'static + Send {
+  pub fn command_with_options<F>(&self, mut msg: interface::RequestMessage,
+                                 options: CommandOptions, callback: F) -> u32
+  where F: FnOnce(Result<interface::ResponseValue, CommandError>) + 'static + Send {
     let mut locked = self.state.lock().unwrap();
-    if locked.commands.len() == 0 {
-      self.writer.send(msg.clone());
+    let id = locked.next_id;
+    locked.next_id = locked.next_id.wrapping_add(1).max(1);
+    msg.set_id(id);
+    if !locked.running {
+      drop(locked);
+      callback(Err(CommandError::Disconnected));
+      return id;
     }
-    let command = Command { 
-            callback: Box::new(callback), 
+    let command = Command {
+            id,
+            callback: Box::new(callback),
             message: msg,
+            options,
+            attempts: 0,
+            sent_at: Instant::now(),
+    };
+    locked.pending.push_back(command);
+    locked.dispatch(&self.writer);
+    id
+  }
+
+  // Counters for the connection so far. Unmatched responses are ones whose
+  // id matched no outstanding request, e.g. duplicated datagrams or replies
+  // to requests that were given up on.
+  pub fn stats(&self) -> Stats {
+    self.state.lock().unwrap().stats
+  }
+
+  // Sends a request and waits for its response. `timeout` bounds the whole
+  // exchange, including retries. Must not be called from a feed or command
+  // callback, as those run on the thread that delivers the response.
+  pub fn request_blocking(&self, msg: interface::RequestMessage, timeout: Duration)
+    -> Result<interface::ResponseValue, Error> {
+    let retries = self.state.lock().unwrap().default_options.retries;
+    let options = CommandOptions { timeout: timeout / retries.saturating_add(1), retries };
+    let (tx, rx) = mpsc::channel();
+    let id = self.command_with_options(msg, options, move |result| {
+      let _ = tx.send(result);
+    });
+    // The command queue enforces the timeout itself; the extra margin only
+    // matters if the receive thread has stopped.
+    let result = match rx.recv_timeout(timeout + Duration::from_millis(500)) {
+      Ok(result) => result,
+      Err(mpsc::RecvTimeoutError::Timeout) => Err(CommandError::Timeout { id, attempts: retries.saturating_add(1) }),
+      Err(mpsc::RecvTimeoutError::Disconnected) => Err(CommandError::Disconnected),
     };
-    locked.commands.push_back(command);
+    Ok(result?)
+  }
+
+  // Queues a request and returns a future resolving to its response
+  pub fn request(&self, msg: interface::RequestMessage) -> ResponseFuture {
+    let (future, completion) = request::pair();
+    self.command(msg, move |result| completion.complete(result));
+    future
+  }
+
+  // Last link change reported by the connection, if it reports them
+  pub fn link_status(&self) -> Option<connection::LinkStatus> {
+    self.state.lock().unwrap().link
+  }
+
+  pub fn set<F>(&self, path: interface::StructurePath, value: interface::ResponseValue, callback: F)
+  where F: FnOnce(Result<interface::ResponseValue, CommandError>) + 'static + Send {
+    let set = interface::RequestMessage::Set{id: 0, path, value};
+    self.command(set, callback);
   }
 }
 
@@ -116,7 +422,295 @@ impl Drop for Manager {
     {
       let mut state = self.state.lock().unwrap();
       state.running = false;
+      // Unblocks the receive thread if it is waiting on a full subscriber
+      for subscriber in &state.subscribers {
+        subscriber.close();
+      }
     }
     self.thread.take().unwrap().join().unwrap();
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use interface::{RequestMessage, ResponseValue};
+
+  const WAIT: Duration = Duration::from_secs(2);
+
+  fn manager() -> (Manager, connection::testing::TestDevice) {
+    let (conn, device) = connection::testing::pair();
+    (Manager::new(Box::new(conn)), device)
+  }
+
+  // Sends `msg` and returns a channel its result arrives on
+  fn send(g: &Manager, msg: RequestMessage)
+    -> mpsc::Receiver<Result<ResponseValue, CommandError>> {
+    let (tx, rx) = mpsc::channel();
+    g.command(msg, move |result| { let _ = tx.send(result); });
+    rx
+  }
+
+  #[test]
+  fn responses_match_by_id() {
+    let (g, device) = manager();
+    let (status_tx, status_rx) = mpsc::channel();
+    g.on_status(move |status| if let Status::UnmatchedResponse { id } = status {
+      let _ = status_tx.send(*id);
+    });
+
+    let result = send(&g, RequestMessage::Ping { id: 0 });
+    let id = device.request(WAIT).unwrap().id();
+    device.respond(id + 1, ResponseValue::Int(1));
+    device.respond(id, ResponseValue::Int(2));
+
+    assert_eq!(result.recv_timeout(WAIT).unwrap().unwrap(), ResponseValue::Int(2));
+    assert_eq!(status_rx.recv_timeout(WAIT).unwrap(), id + 1);
+    assert_eq!(g.stats().unmatched_responses, 1);
+  }
+
+  #[test]
+  fn requests_get_unique_ids() {
+    let (g, device) = manager();
+    let first = send(&g, RequestMessage::Ping { id: 5 });
+    let second = send(&g, RequestMessage::Ping { id: 5 });
+
+    let first_id = device.request(WAIT).unwrap().id();
+    device.respond(first_id, ResponseValue::Int(1));
+    let second_id = device.request(WAIT).unwrap().id();
+    device.respond(second_id, ResponseValue::Int(2));
+
+    assert_ne!(first_id, second_id);
+    assert_eq!(first.recv_timeout(WAIT).unwrap().unwrap(), ResponseValue::Int(1));
+    assert_eq!(second.recv_timeout(WAIT).unwrap().unwrap(), ResponseValue::Int(2));
+  }
+
+  #[test]
+  fn resends_until_answered() {
+    let (g, device) = manager();
+    let options = CommandOptions { timeout: Duration::from_millis(50), retries: 2 };
+    let (tx, result) = mpsc::channel();
+    g.command_with_options(RequestMessage::Ping { id: 0 }, options, move |r| { let _ = tx.send(r); });
+
+    let id = device.request(WAIT).unwrap().id();
+    // A resend keeps the id, so the reply to either attempt completes it
+    assert_eq!(device.request(WAIT).unwrap().id(), id);
+    device.respond(id, ResponseValue::Int(1));
+
+    assert_eq!(result.recv_timeout(WAIT).unwrap().unwrap(), ResponseValue::Int(1));
+    assert_eq!(g.stats().command_retries, 1);
+    assert_eq!(g.stats().command_timeouts, 0);
+  }
+
+  #[test]
+  fn times_out_after_retries() {
+    let (g, device) = manager();
+    let (status_tx, status_rx) = mpsc::channel();
+    g.on_status(move |status| if let Status::CommandTimeout { attempts, .. } = status {
+      let _ = status_tx.send(*attempts);
+    });
+    let options = CommandOptions { timeout: Duration::from_millis(20), retries: 2 };
+    let (tx, result) = mpsc::channel();
+    g.command_with_options(RequestMessage::Ping { id: 0 }, options, move |r| { let _ = tx.send(r); });
+
+    let attempts = std::iter::from_fn(|| device.request(Duration::from_millis(200))).count();
+    assert_eq!(attempts, 3);
+    assert!(matches!(result.recv_timeout(WAIT).unwrap(), Err(CommandError::Timeout { attempts: 3, .. })));
+    assert_eq!(status_rx.recv_timeout(WAIT).unwrap(), 3);
+    assert_eq!(g.stats().command_timeouts, 1);
+  }
+
+  #[test]
+  fn queued_commands_wait_for_the_one_in_flight() {
+    let (g, device) = manager();
+    let first = send(&g, RequestMessage::Ping { id: 0 });
+    let _second = send(&g, RequestMessage::Structure { id: 0 });
+
+    let id = device.request(WAIT).unwrap().id();
+    assert!(device.request(Duration::from_millis(100)).is_none());
+    device.respond(id, ResponseValue::None);
+    assert!(matches!(device.request(WAIT), Some(RequestMessage::Structure { .. })));
+    assert!(first.recv_timeout(WAIT).unwrap().is_ok());
+  }
+
+  #[test]
+  fn pending_commands_fail_when_dropped() {
+    let (g, device) = manager();
+    let result = send(&g, RequestMessage::Ping { id: 0 });
+    device.request(WAIT).unwrap();
+    drop(g);
+    assert!(matches!(result.recv_timeout(WAIT).unwrap(), Err(CommandError::Disconnected)));
+  }
+
+  // Just enough of an executor to wait on a ResponseFuture
+  fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct Unpark(thread::Thread);
+    impl std::task::Wake for Unpark {
+      fn wake(self: Arc<Self>) {
+        self.0.unpark();
+      }
+    }
+    let waker = std::task::Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+      if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+        return output;
+      }
+      thread::park();
+    }
+  }
+
+  #[test]
+  fn request_blocking_returns_the_response() {
+    let (g, device) = manager();
+    let responder = thread::spawn(move || {
+      let id = device.request(WAIT).unwrap().id();
+      device.respond(id, ResponseValue::Str("ok".to_string()));
+      device
+    });
+    let response = g.request_blocking(RequestMessage::Ping { id: 0 }, WAIT).unwrap();
+    assert_eq!(response, ResponseValue::Str("ok".to_string()));
+    responder.join().unwrap();
+  }
+
+  #[test]
+  fn request_blocking_times_out() {
+    let (g, _device) = manager();
+    g.set_command_options(CommandOptions { timeout: Duration::from_millis(10), retries: u32::MAX });
+    let result = g.request_blocking(RequestMessage::Ping { id: 0 }, Duration::from_millis(50));
+    assert!(matches!(result, Err(Error::Command(CommandError::Timeout { .. }))));
+  }
+
+  #[test]
+  fn request_future_resolves() {
+    let (g, device) = manager();
+    let future = g.request(RequestMessage::Ping { id: 0 });
+    let id = device.request(WAIT).unwrap().id();
+    device.respond(id, ResponseValue::Int(3));
+    assert_eq!(block_on(future).unwrap(), ResponseValue::Int(3));
+  }
+
+  #[test]
+  fn request_future_fails_when_dropped() {
+    let (g, _device) = manager();
+    let future = g.request(RequestMessage::Ping { id: 0 });
+    drop(g);
+    assert!(matches!(block_on(future), Err(Error::Command(CommandError::Disconnected))));
+  }
+
+  #[test]
+  fn error_response_is_rejected() {
+    let (g, device) = manager();
+    let result = send(&g, RequestMessage::Get { id: 0, path: interface::StructurePath::new() });
+    let id = device.request(WAIT).unwrap().id();
+    device.respond(id, ResponseValue::Error(interface::ResponseError { error: "invalid path".to_string() }));
+    assert!(matches!(result.recv_timeout(WAIT).unwrap(), Err(CommandError::Rejected(e)) if e == "invalid path"));
+  }
+
+  #[test]
+  fn link_changes_are_reported() {
+    use connection::{LinkStatus, RxEvent};
+    let (g, device) = manager();
+    let (status_tx, status_rx) = mpsc::channel();
+    g.on_status(move |status| match status {
+      Status::Link(link) => { let _ = status_tx.send(Some(*link)); },
+      Status::FeedDropped(_) => { let _ = status_tx.send(None); },
+      _ => (),
+    });
+    let feed = g.subscribe(SubscribeOptions::default());
+
+    device.send(interface::Message::Description { keys: vec!["rpm".to_string()] });
+    for link in [LinkStatus::Connected, LinkStatus::Disconnected, LinkStatus::Disconnected,
+                 LinkStatus::Reconnecting, LinkStatus::Connected] {
+      device.send_event(RxEvent::Link(link));
+    }
+    // The device may have been reflashed, so this waits for a description
+    device.send(interface::Message::Feed { values: vec![interface::FeedValue::Int(1)] });
+
+    let reported = (0..5).map(|_| status_rx.recv_timeout(WAIT).unwrap()).collect::<Vec<_>>();
+    assert_eq!(reported, [Some(LinkStatus::Connected), Some(LinkStatus::Disconnected),
+                          Some(LinkStatus::Reconnecting), Some(LinkStatus::Connected), None]);
+    assert_eq!(g.link_status(), Some(LinkStatus::Connected));
+    assert_eq!(g.stats().reconnects, 1);
+    assert!(feed.try_recv().is_none());
+  }
+
+  #[test]
+  fn connection_errors_are_reported() {
+    let (g, device) = manager();
+    let (status_tx, status_rx) = mpsc::channel();
+    g.on_status(move |status| match status {
+      Status::DecodeError(e) => { let _ = status_tx.send(format!("decode: {e}")); },
+      Status::Error(e) => { let _ = status_tx.send(format!("error: {e}")); },
+      _ => (),
+    });
+
+    device.send_event(connection::RxEvent::Error(Error::Decode("bad packet".to_string())));
+    device.send_event(connection::RxEvent::Error(Error::Other("write failed".to_string())));
+    assert_eq!(status_rx.recv_timeout(WAIT).unwrap(), "decode: undecodable message: bad packet");
+    assert_eq!(status_rx.recv_timeout(WAIT).unwrap(), "error: write failed");
+    let stats = g.stats();
+    assert_eq!((stats.decode_errors, stats.errors), (1, 1));
+  }
+
+  #[test]
+  fn status_callback_can_use_the_manager() {
+    let (g, device) = manager();
+    // Left running, the callback needs the Manager to outlive it
+    let g : &'static Manager = Box::leak(Box::new(g));
+    let (stats_tx, stats_rx) = mpsc::channel();
+    g.on_status(move |status| if let Status::DecodeError(_) = status {
+      let _ = stats_tx.send((g.stats().decode_errors, g.link_status()));
+    });
+    device.send_event(connection::RxEvent::Error(Error::Decode("bad packet".to_string())));
+    assert_eq!(stats_rx.recv_timeout(WAIT).unwrap(), (1, None));
+  }
+
+  #[test]
+  fn statuses_are_counted_without_a_callback() {
+    let (g, device) = manager();
+    device.send_event(connection::RxEvent::Error(Error::Decode("bad packet".to_string())));
+    device.send(interface::Message::Feed { values: vec![] });
+    let deadline = Instant::now() + WAIT;
+    while g.stats().dropped_feeds == 0 && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(10));
+    }
+    let stats = g.stats();
+    assert_eq!((stats.decode_errors, stats.dropped_feeds), (1, 1));
+  }
+
+  #[test]
+  fn every_subscriber_gets_each_frame() {
+    let (g, device) = manager();
+    let feeds = [g.subscribe(SubscribeOptions::default()), g.subscribe(SubscribeOptions::default())];
+    device.send(interface::Message::Description { keys: vec!["rpm".to_string()] });
+    for rpm in [800, 900] {
+      device.send(interface::Message::Feed { values: vec![interface::FeedValue::Int(rpm)] });
+    }
+    for feed in &feeds {
+      for rpm in [800.0, 900.0] {
+        assert_eq!(feed.recv_timeout(WAIT).unwrap().get_f64("rpm"), Some(rpm));
+      }
+    }
+    drop(g);
+    assert!(feeds.iter().all(|feed| feed.recv().is_none()));
+  }
+
+  #[test]
+  fn repeated_description_keeps_the_schema() {
+    let (g, device) = manager();
+    let feed = g.subscribe(SubscribeOptions::default());
+    let mut frames = vec![];
+    for keys in [["rpm"], ["rpm"], ["map"]] {
+      device.send(interface::Message::Description { keys: keys.iter().map(|k| k.to_string()).collect() });
+      device.send(interface::Message::Feed { values: vec![interface::FeedValue::Int(1)] });
+      frames.push(feed.recv_timeout(WAIT).unwrap());
+    }
+    assert!(Arc::ptr_eq(frames[0].schema(), frames[1].schema()));
+    assert!(!Arc::ptr_eq(frames[1].schema(), frames[2].schema()));
+    assert_eq!(frames[2].keys(), ["map"]);
+    let stats = g.stats();
+    assert_eq!((stats.descriptions, stats.description_changes), (3, 2));
+  }
+}
@@ -1,112 +1,423 @@
 use std::sync::mpsc;
 use std::thread;
 use std::time::SystemTime;
+#[allow(clippy::single_component_path_imports)]
 use sqlite;
 
 use crate::interface;
+use crate::{ConfigSnapshot, Error};
 
 enum LogMessage {
     FeedPoint {
         time: SystemTime,
         values: Vec<interface::FeedValue>,
     },
+    Description(Vec<String>),
+    Config(ConfigSnapshot),
     Terminate,
 }
 
+// Describes one recording. Every point written by a LogFeedWriter is tagged
+// with the id of the `sessions` row it creates from this.
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub host: Option<String>,
+    // How the device was reached, as a `connection::ConnectionUri`
+    pub connection: Option<String>,
+    // Identifies the particular device, e.g. its USB serial number
+    pub device: Option<String>,
+    // As reported by the device, None if it doesn't say
+    pub firmware_version: Option<String>,
+    pub tool_version: Option<String>,
+}
+
+impl SessionInfo {
+    // Fills in the host and tool version
+    pub fn new(connection: &str) -> SessionInfo {
+        SessionInfo {
+            host: gethostname::gethostname().into_string().ok(),
+            connection: Some(connection.to_string()),
+            tool_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+// How a channel's values are stored. Taken from the first value written to
+// a new column, and recovered from the column's declared type on reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelType {
+    Int,
+    Float,
+}
+
+impl ChannelType {
+    fn of(value: &interface::FeedValue) -> ChannelType {
+        match value {
+            interface::FeedValue::Int(_) => ChannelType::Int,
+            interface::FeedValue::Float(_) => ChannelType::Float,
+        }
+    }
+
+    fn sql_type(self) -> &'static str {
+        match self {
+            ChannelType::Int => "INTEGER",
+            ChannelType::Float => "REAL",
+        }
+    }
+
+    // Logs written before types were recorded declare everything REAL
+    fn from_sql_type(decl: &str) -> ChannelType {
+        if decl.eq_ignore_ascii_case("INTEGER") { ChannelType::Int } else { ChannelType::Float }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogChannel {
+    pub name: String,
+    pub channel_type: ChannelType,
+}
+
+// The feed channels recorded in a log, in column order
+pub(crate) fn read_channels(conn: &sqlite::Connection) -> Result<Vec<LogChannel>, Error> {
+    let mut channels = vec![];
+    for row in conn.prepare("PRAGMA TABLE_INFO(points);")?.into_iter() {
+        let row = row?;
+        let name = row.read::<&str, _>("name");
+        if name != "realtime_ns" && name != "session_id" {
+            channels.push(LogChannel {
+                name: name.to_string(),
+                channel_type: ChannelType::from_sql_type(row.read::<&str, _>("type")),
+            });
+        }
+    }
+    Ok(channels)
+}
+
+// Column names come from the device, so they're quoted as identifiers
+pub(crate) fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn epoch_ns(time: SystemTime) -> Result<i64, Error> {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| Error::Other(e.to_string()))?
+        .as_nanos().try_into()
+        .map_err(|_| Error::Other("timestamp out of range".to_string()))
+}
+
 pub struct LogFeedWriter {
     tx: mpsc::Sender::<LogMessage>,
+    errors: mpsc::Receiver<Error>,
     handle: Option<thread::JoinHandle<()>>,
 }
 
 impl Drop for LogFeedWriter {
   fn drop(&mut self) {
-    self.tx.send(LogMessage::Terminate).unwrap();
-    let handle = self.handle.take();
-    handle.unwrap().join().unwrap();
+    let _ = self.tx.send(LogMessage::Terminate);
+    if let Some(handle) = self.handle.take() {
+      let _ = handle.join();
+    }
   }
 }
 
 impl LogFeedWriter {
-    fn ensure_columns(keys: &Vec<String>, conn: &sqlite::Connection) {
+    // Adds a column for each key the log lacks, typed after the matching
+    // value
+    #[allow(clippy::len_zero, clippy::redundant_pattern_matching)]
+    fn ensure_columns(keys: &[String], values: &[interface::FeedValue], conn: &sqlite::Connection) -> Result<(), Error> {
       let mut current_keys : Vec<String> = vec![];
-      for row in conn.prepare("PRAGMA TABLE_INFO(points);").unwrap()
-          .into_iter().map(|r| r.unwrap()) {
-              current_keys.push(row.read::<&str, _>("name").to_string());
+      for row in conn.prepare("PRAGMA TABLE_INFO(points);")?.into_iter() {
+              current_keys.push(row?.read::<&str, _>("name").to_string());
       }
 
       if current_keys.len() == 0 {
         // Create table
-          conn.execute("CREATE TABLE points (realtime_ns INTEGER);").unwrap();
+          conn.execute("CREATE TABLE points (realtime_ns INTEGER, session_id INTEGER);")?;
+      } else if !current_keys.iter().any(|k| k == "session_id") {
+          // Written before sessions were recorded
+          conn.execute("ALTER TABLE points ADD COLUMN session_id INTEGER;")?;
       }
 
-      for new_key in keys {
+      for (new_key, value) in keys.iter().zip(values) {
         if let None = current_keys.iter().find(|&x| x == new_key) {
           // Not currently there, alter table to add it
-          conn.execute(format!("ALTER TABLE points ADD COLUMN '{}' REAL;",
-          new_key)).unwrap();
+          conn.execute(format!("ALTER TABLE points ADD COLUMN {} {};",
+          quote(new_key), ChannelType::of(value).sql_type()))?;
         }
       }
+      Ok(())
     }
 
-    pub fn new(filename: &str, keys: Vec<String>) -> LogFeedWriter {
+    fn start_session(session: &SessionInfo, conn: &sqlite::Connection) -> Result<i64, Error> {
+      conn.execute("CREATE TABLE IF NOT EXISTS sessions (
+          id INTEGER PRIMARY KEY,
+          start_ns INTEGER NOT NULL,
+          stop_ns INTEGER,
+          host TEXT,
+          connection TEXT,
+          device TEXT,
+          firmware_version TEXT,
+          tool_version TEXT);
+        CREATE TABLE IF NOT EXISTS config_snapshots (
+          id INTEGER PRIMARY KEY,
+          session_id INTEGER NOT NULL REFERENCES sessions(id),
+          realtime_ns INTEGER NOT NULL,
+          structure TEXT,
+          config TEXT);")?;
+
+      let mut stmt = conn.prepare("INSERT INTO sessions
+          (start_ns, host, connection, device, firmware_version, tool_version)
+          VALUES (?, ?, ?, ?, ?, ?) RETURNING id")?;
+      stmt.bind((1, epoch_ns(SystemTime::now())?))?;
+      stmt.bind((2, session.host.as_deref()))?;
+      stmt.bind((3, session.connection.as_deref()))?;
+      stmt.bind((4, session.device.as_deref()))?;
+      stmt.bind((5, session.firmware_version.as_deref()))?;
+      stmt.bind((6, session.tool_version.as_deref()))?;
+      stmt.next()?;
+      Ok(stmt.read::<i64, _>(0)?)
+    }
+
+    // Kept current while recording, so a session that was cut short still
+    // has an approximate end
+    fn stop_session(session_id: i64, conn: &sqlite::Connection) -> Result<(), Error> {
+      let mut stmt = conn.prepare("UPDATE sessions SET stop_ns = ? WHERE id = ?")?;
+      stmt.bind((1, epoch_ns(SystemTime::now())?))?;
+      stmt.bind((2, session_id))?;
+      stmt.next()?;
+      Ok(())
+    }
+
+    fn write_config(session_id: i64, config: &ConfigSnapshot, conn: &sqlite::Connection) -> Result<(), Error> {
+      let mut stmt = conn.prepare("INSERT INTO config_snapshots
+          (session_id, realtime_ns, structure, config) VALUES (?, ?, ?, ?)")?;
+      stmt.bind((1, session_id))?;
+      stmt.bind((2, epoch_ns(SystemTime::now())?))?;
+      stmt.bind((3, serde_json::to_string(&config.structure)?.as_str()))?;
+      stmt.bind((4, serde_json::to_string(&config.values)?.as_str()))?;
+      stmt.next()?;
+      Ok(())
+    }
+
+    pub fn new(filename: &str, keys: Vec<String>, session: SessionInfo) -> Result<LogFeedWriter, Error> {
         let (tx, rx) = mpsc::channel::<LogMessage>();
+        let (errors_tx, errors) = mpsc::channel::<Error>();
 
-        let conn = sqlite::open(filename).unwrap();
-        conn.execute("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL; ").unwrap();
-        LogFeedWriter::ensure_columns(&keys, &conn);
+        let conn = sqlite::open(filename)?;
+        conn.execute("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL; ")?;
+        // Columns for the keys are added once the first point shows their
+        // types
+        LogFeedWriter::ensure_columns(&[], &[], &conn)?;
+        // Readers look points up by time
+        conn.execute("CREATE INDEX IF NOT EXISTS points_realtime ON points(realtime_ns);")?;
+        let session_id = LogFeedWriter::start_session(&session, &conn)?;
 
         let thr = thread::Builder::new().name("sqlite-feed-writer".to_string()).spawn(move || {
+            let report = |result: Result<(), Error>| {
+                if let Err(e) = result {
+                    let _ = errors_tx.send(e);
+                }
+            };
 
-            let insert_cols = keys
-                .iter()
-                .map(|_| "?")
-                .collect::<Vec<&str>>()
-                .join(", ");
-            let insert_names = keys
-                .iter()
-                .map(|x| format!("'{x}'"))
-                .collect::<Vec<String>>()
-                .join(", ");
-
-            let mut stmt = conn.prepare(format!("insert into points (realtime_ns, {insert_names}) values (?, {insert_cols})")).unwrap();
+            let mut keys = keys;
+            // Prepared for the current keys by the first point to use them
+            let mut stmt : Option<sqlite::Statement> = None;
 
-            let mut remaining = 0; 
+            let mut remaining = 0;
             while let Ok(val) = rx.recv() {
                 match val {
                     LogMessage::FeedPoint{time, values} => {
+                        if values.len() != keys.len() {
+                            report(Err(Error::FeedLength { expected: keys.len(), received: values.len() }));
+                            continue;
+                        }
                         if remaining == 0 {
-                            conn.execute("BEGIN;").unwrap();
+                            report(conn.execute("BEGIN;").map_err(Error::from));
                             remaining = 5000;
                         }
-                        LogFeedWriter::write(&mut stmt, time, values);
+                        if stmt.is_none() {
+                            let prepared = LogFeedWriter::ensure_columns(&keys, &values, &conn)
+                                .and_then(|_| Ok(conn.prepare(LogFeedWriter::insert_statement(&keys))?));
+                            match prepared {
+                                Ok(new_stmt) => stmt = Some(new_stmt),
+                                Err(e) => report(Err(e)),
+                            }
+                        }
+                        // A point that fails to insert is lost, but the
+                        // recording carries on
+                        if let Some(stmt) = &mut stmt {
+                            report(LogFeedWriter::write(stmt, session_id, time, values));
+                        }
                         remaining -= 1;
                         if remaining == 0 {
-                            conn.execute("COMMIT;").unwrap();
+                            report(LogFeedWriter::stop_session(session_id, &conn));
+                            report(conn.execute("COMMIT;").map_err(Error::from));
                         }
                     },
+                    LogMessage::Description(new_keys) => {
+                        // Columns the new description lacks are left NULL
+                        // from here on
+                        keys = new_keys;
+                        stmt = None;
+                    },
+                    LogMessage::Config(config) =>
+                        report(LogFeedWriter::write_config(session_id, &config, &conn)),
                     LogMessage::Terminate => break,
                 }
             }
-            conn.execute("COMMIT;").unwrap();
-        }).unwrap();
-        LogFeedWriter{ tx, handle: Some(thr) }
+            report(LogFeedWriter::stop_session(session_id, &conn));
+            if remaining != 0 {
+                report(conn.execute("COMMIT;").map_err(Error::from));
+            }
+        })?;
+        Ok(LogFeedWriter{ tx, errors, handle: Some(thr) })
+    }
+
+    pub fn add(&self, time: SystemTime, values: Vec<interface::FeedValue>) -> Result<(), Error> {
+      self.tx.send(LogMessage::FeedPoint{time, values}).map_err(|_| Error::Disconnected)
+    }
+
+    // Switches to a new feed layout, e.g. after the firmware was reflashed.
+    // Points added after this are written to the columns named in `keys`,
+    // which are created if the log doesn't have them yet.
+    pub fn set_keys(&self, keys: Vec<String>) -> Result<(), Error> {
+      self.tx.send(LogMessage::Description(keys)).map_err(|_| Error::Disconnected)
+    }
+
+    // Stores the device's configuration against this session
+    pub fn add_config(&self, config: ConfigSnapshot) -> Result<(), Error> {
+      self.tx.send(LogMessage::Config(config)).map_err(|_| Error::Disconnected)
+    }
+
+    // Errors the writer thread has hit since the last call
+    pub fn take_errors(&self) -> Vec<Error> {
+      self.errors.try_iter().collect()
     }
 
-    pub fn add(&self, time: SystemTime, values: Vec<interface::FeedValue>) {
-      self.tx.send(LogMessage::FeedPoint{time, values}).unwrap();
+    fn insert_statement(keys: &[String]) -> String {
+        let insert_cols = keys
+            .iter()
+            .map(|_| "?")
+            .collect::<Vec<&str>>()
+            .join(", ");
+        let insert_names = keys
+            .iter()
+            .map(|x| quote(x))
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!("insert into points (realtime_ns, session_id, {insert_names}) values (?, ?, {insert_cols})")
     }
 
-    fn write(stmt: &mut sqlite::Statement, time: SystemTime, vals: Vec<interface::FeedValue>) {
-        let epoch_time : i64 = time.duration_since(SystemTime::UNIX_EPOCH).unwrap()
-            .as_nanos().try_into().unwrap();
-        stmt.reset().unwrap();
-        stmt.bind((1, epoch_time)).unwrap();
+    fn write(stmt: &mut sqlite::Statement, session_id: i64, time: SystemTime, vals: Vec<interface::FeedValue>) -> Result<(), Error> {
+        stmt.reset()?;
+        stmt.bind((1, epoch_ns(time)?))?;
+        stmt.bind((2, session_id))?;
         for (i, v) in vals.iter().enumerate() {
-            match v { interface::FeedValue::Int(x) => stmt.bind((i + 2, *x as i64)),
-                      interface::FeedValue::Float(x) => stmt.bind((i + 2, *x as f64)),
-                      }.unwrap();
+            match v { interface::FeedValue::Int(x) => stmt.bind((i + 3, *x as i64)),
+                      interface::FeedValue::Float(x) => stmt.bind((i + 3, *x as f64)),
+                      }?;
         }
-        stmt.next().unwrap();
+        stmt.next()?;
+        Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::interface::FeedValue;
+    use crate::testing::{self, TempFile};
+    use crate::LogReader;
+
+    // Waits for the writer thread to report something
+    fn wait_for_errors(writer: &LogFeedWriter) -> Vec<Error> {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            let errors = writer.take_errors();
+            if !errors.is_empty() || Instant::now() > deadline {
+                return errors;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn mismatched_feed_is_reported() {
+        let file = TempFile::new("sq3");
+        let writer = LogFeedWriter::new(file.path(), vec!["rpm".to_string()], SessionInfo::default()).unwrap();
+        writer.add(testing::log_start(), vec![FeedValue::Int(1), FeedValue::Int(2)]).unwrap();
+        let errors = wait_for_errors(&writer);
+        assert!(matches!(errors[..], [Error::FeedLength { expected: 1, received: 2 }]));
+    }
+
+    #[test]
+    fn unwritable_path_is_an_error() {
+        let file = TempFile::new("sq3");
+        let path = format!("{}/missing/log.sq3", file.path());
+        assert!(LogFeedWriter::new(&path, vec![], SessionInfo::default()).is_err());
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    // Each frame's values as floats, with NaN for gaps
+    fn read_back(path: &str) -> (Vec<String>, Vec<Vec<f32>>) {
+        let reader = LogReader::open(path).unwrap();
+        let names = reader.channels().iter().map(|c| c.name.clone()).collect();
+        let rows = reader.frames().unwrap()
+            .map(|frame| frame.unwrap().values().iter()
+                .map(|v| match v { FeedValue::Int(x) => *x as f32, FeedValue::Float(x) => *x })
+                .collect())
+            .collect();
+        (names, rows)
+    }
+
+    #[test]
+    fn follows_description_changes() {
+        let file = TempFile::new("sq3");
+        let writer = LogFeedWriter::new(file.path(), keys(&["rpm"]), SessionInfo::default()).unwrap();
+        let at = |ms| testing::log_start() + Duration::from_millis(ms);
+        writer.add(at(0), vec![FeedValue::Int(1)]).unwrap();
+        writer.set_keys(keys(&["rpm", "map"])).unwrap();
+        writer.add(at(1), vec![FeedValue::Int(2), FeedValue::Float(3.5)]).unwrap();
+        writer.set_keys(keys(&["map"])).unwrap();
+        writer.add(at(2), vec![FeedValue::Float(4.5)]).unwrap();
+        drop(writer);
+
+        let (names, rows) = read_back(file.path());
+        assert_eq!(names, ["rpm", "map"]);
+        assert_eq!(rows[0][0], 1.0);
+        assert!(rows[0][1].is_nan());
+        assert_eq!(rows[1], [2.0, 3.5]);
+        assert!(rows[2][0].is_nan());
+        assert_eq!(rows[2][1], 4.5);
+    }
+
+    #[test]
+    fn columns_are_typed_from_the_first_values() {
+        let log = testing::write_log(&["rpm", "map"], &[
+            (0, vec![FeedValue::Int(800), FeedValue::Float(30.5)]),
+        ]);
+        let reader = LogReader::open(log.path()).unwrap();
+        let types = reader.channels().iter().map(|c| c.channel_type).collect::<Vec<_>>();
+        assert_eq!(types, [ChannelType::Int, ChannelType::Float]);
+        let frame = reader.frames().unwrap().next().unwrap().unwrap();
+        assert_eq!(frame.values(), [FeedValue::Int(800), FeedValue::Float(30.5)]);
+    }
+
+    #[test]
+    fn untyped_logs_read_as_floats() {
+        let file = TempFile::new("sq3");
+        let conn = sqlite::open(file.path()).unwrap();
+        conn.execute("CREATE TABLE points (realtime_ns INTEGER, rpm REAL);
+                      INSERT INTO points VALUES (0, 800);").unwrap();
+        let reader = LogReader::open(file.path()).unwrap();
+        assert_eq!(reader.channels()[0].channel_type, ChannelType::Float);
+        let frame = reader.frames().unwrap().next().unwrap().unwrap();
+        assert_eq!(frame.values(), [FeedValue::Float(800.0)]);
+    }
+}
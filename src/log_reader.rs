@@ -0,0 +1,313 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::interface;
+use crate::log::{self, quote, ChannelType, LogChannel};
+use crate::{Error, FeedFrame, FeedSchema, SessionInfo};
+
+fn from_epoch_ns(ns: i64) -> SystemTime {
+  SystemTime::UNIX_EPOCH + Duration::from_nanos(ns.max(0) as u64)
+}
+
+// Times outside what the log can hold clamp to its limits
+fn to_epoch_ns(time: SystemTime) -> i64 {
+  match time.duration_since(SystemTime::UNIX_EPOCH) {
+    Ok(d) => d.as_nanos().try_into().unwrap_or(i64::MAX),
+    Err(_) => 0,
+  }
+}
+
+// Points are only ever written from FeedValues, so anything else is a gap
+// left by a channel missing from the description at the time
+fn read_value(channel_type: ChannelType, value: sqlite::Value) -> interface::FeedValue {
+  match (channel_type, value) {
+    (ChannelType::Int, sqlite::Value::Integer(x)) => interface::FeedValue::Int(x as u32),
+    (_, sqlite::Value::Integer(x)) => interface::FeedValue::Float(x as f32),
+    (_, sqlite::Value::Float(x)) => interface::FeedValue::Float(x as f32),
+    _ => interface::FeedValue::Float(f32::NAN),
+  }
+}
+
+// A row of the `sessions` table, see `SessionInfo`
+#[derive(Debug, Clone)]
+pub struct LogSession {
+  pub id: i64,
+  pub start: SystemTime,
+  // None if the recording never got as far as its first commit
+  pub stop: Option<SystemTime>,
+  pub info: SessionInfo,
+}
+
+// Reads back a log written by `LogFeedWriter`
+pub struct LogReader {
+  conn: sqlite::Connection,
+  channels: Vec<LogChannel>,
+}
+
+impl LogReader {
+  pub fn open(filename: &str) -> Result<LogReader, Error> {
+    let flags = sqlite::OpenFlags::new().with_read_only();
+    let conn = sqlite::Connection::open_with_flags(filename, flags)?;
+    let channels = log::read_channels(&conn)?;
+    Ok(LogReader { conn, channels })
+  }
+
+  pub fn channels(&self) -> &[LogChannel] {
+    &self.channels
+  }
+
+  // Times of the first and last points, None if there are none
+  pub fn time_span(&self) -> Result<Option<(SystemTime, SystemTime)>, Error> {
+    if self.channels.is_empty() {
+      return Ok(None);
+    }
+    let mut stmt = self.conn.prepare("SELECT min(realtime_ns), max(realtime_ns) FROM points")?;
+    stmt.next()?;
+    match (stmt.read::<Option<i64>, _>(0)?, stmt.read::<Option<i64>, _>(1)?) {
+      (Some(first), Some(last)) => Ok(Some((from_epoch_ns(first), from_epoch_ns(last)))),
+      _ => Ok(None),
+    }
+  }
+
+  // Recordings in the log, oldest first. Empty for logs written before
+  // sessions were recorded.
+  pub fn sessions(&self) -> Result<Vec<LogSession>, Error> {
+    let mut exists = self.conn.prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'sessions'")?;
+    if exists.next()? == sqlite::State::Done {
+      return Ok(vec![]);
+    }
+    let mut sessions = vec![];
+    let query = "SELECT id, start_ns, stop_ns, host, connection, device, firmware_version, tool_version
+                 FROM sessions ORDER BY start_ns";
+    for row in self.conn.prepare(query)?.into_iter() {
+      let row = row?;
+      let text = |column: &str| -> Result<Option<String>, Error> {
+        Ok(row.try_read::<Option<&str>, _>(column)?.map(str::to_string))
+      };
+      sessions.push(LogSession {
+        id: row.try_read::<i64, _>("id")?,
+        start: from_epoch_ns(row.try_read::<i64, _>("start_ns")?),
+        stop: row.try_read::<Option<i64>, _>("stop_ns")?.map(from_epoch_ns),
+        info: SessionInfo {
+          host: text("host")?,
+          connection: text("connection")?,
+          device: text("device")?,
+          firmware_version: text("firmware_version")?,
+          tool_version: text("tool_version")?,
+        },
+      });
+    }
+    Ok(sessions)
+  }
+
+  // Every point in the log, with every channel
+  pub fn frames(&self) -> Result<LogFrames<'_>, Error> {
+    self.query().frames()
+  }
+
+  pub fn query(&self) -> LogQuery<'_> {
+    LogQuery { reader: self, channels: None, start: None, end: None }
+  }
+}
+
+// Narrows down the frames read from a log
+pub struct LogQuery<'a> {
+  reader: &'a LogReader,
+  channels: Option<Vec<String>>,
+  start: Option<SystemTime>,
+  end: Option<SystemTime>,
+}
+
+impl<'a> LogQuery<'a> {
+  // Only read these channels, in this order
+  pub fn channels<S: AsRef<str>>(mut self, names: &[S]) -> Self {
+    self.channels = Some(names.iter().map(|n| n.as_ref().to_string()).collect());
+    self
+  }
+
+  // Skip points before `start`
+  pub fn start(mut self, start: SystemTime) -> Self {
+    self.start = Some(start);
+    self
+  }
+
+  // Stop before the first point at or after `end`
+  pub fn end(mut self, end: SystemTime) -> Self {
+    self.end = Some(end);
+    self
+  }
+
+  pub fn frames(self) -> Result<LogFrames<'a>, Error> {
+    let channels : Vec<LogChannel> = match &self.channels {
+      None => self.reader.channels.clone(),
+      Some(names) => names.iter()
+        .map(|name| self.reader.channels.iter()
+          .find(|c| &c.name == name)
+          .cloned()
+          .ok_or_else(|| Error::Other(format!("log has no channel named {name}"))))
+        .collect::<Result<_, Error>>()?,
+    };
+
+    let columns = channels.iter()
+      .map(|c| format!(", {}", quote(&c.name)))
+      .collect::<String>();
+    let stmt = self.reader.conn.prepare(format!(
+        "SELECT realtime_ns{columns} FROM points
+         WHERE realtime_ns >= ? AND realtime_ns < ? ORDER BY realtime_ns"))?;
+
+    let schema = Arc::new(FeedSchema::new(channels.iter().map(|c| c.name.clone()).collect()));
+    let start_ns = self.start.map(to_epoch_ns).unwrap_or(i64::MIN);
+    let end_ns = self.end.map(to_epoch_ns).unwrap_or(i64::MAX);
+    let mut frames = LogFrames { stmt, channels, schema, start_ns, end_ns };
+    frames.bind(start_ns)?;
+    Ok(frames)
+  }
+}
+
+// Iterates over a log's points in time order
+pub struct LogFrames<'a> {
+  stmt: sqlite::Statement<'a>,
+  channels: Vec<LogChannel>,
+  schema: Arc<FeedSchema>,
+  start_ns: i64,
+  end_ns: i64,
+}
+
+impl LogFrames<'_> {
+  fn bind(&mut self, from_ns: i64) -> Result<(), Error> {
+    self.stmt.reset()?;
+    self.stmt.bind((1, from_ns.max(self.start_ns)))?;
+    self.stmt.bind((2, self.end_ns))?;
+    Ok(())
+  }
+
+  pub fn schema(&self) -> &Arc<FeedSchema> {
+    &self.schema
+  }
+
+  // Continues from the first point at or after `time`, either direction.
+  // Never moves outside the query's time window.
+  pub fn seek(&mut self, time: SystemTime) -> Result<(), Error> {
+    self.bind(to_epoch_ns(time))
+  }
+
+  fn read_frame(&mut self) -> Result<Option<FeedFrame>, Error> {
+    if self.stmt.next()? == sqlite::State::Done {
+      return Ok(None);
+    }
+    let time = from_epoch_ns(self.stmt.read::<i64, _>(0)?);
+    let mut values = Vec::with_capacity(self.channels.len());
+    for (i, channel) in self.channels.iter().enumerate() {
+      values.push(read_value(channel.channel_type, self.stmt.read::<sqlite::Value, _>(i + 1)?));
+    }
+    Ok(Some(FeedFrame::new(time, self.schema.clone(), values)?))
+  }
+}
+
+impl Iterator for LogFrames<'_> {
+  type Item = Result<FeedFrame, Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.read_frame().transpose()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::interface::FeedValue;
+  use crate::testing::{self, TempFile};
+  use crate::LogFeedWriter;
+
+  #[test]
+  fn sessions_round_trip() {
+    let file = TempFile::new("sq3");
+    // Only the first device reports a firmware version
+    for (device, firmware) in [("0483:5740 A1", Some("1.2.0")), ("0483:5740 B2", None)] {
+      let session = SessionInfo {
+        device: Some(device.to_string()),
+        firmware_version: firmware.map(str::to_string),
+        ..SessionInfo::new("usb")
+      };
+      let writer = LogFeedWriter::new(file.path(), vec!["rpm".to_string()], session).unwrap();
+      writer.add(testing::log_start(), vec![FeedValue::Int(1)]).unwrap();
+    }
+
+    let sessions = LogReader::open(file.path()).unwrap().sessions().unwrap();
+    assert_eq!(sessions.len(), 2);
+    for (session, device) in sessions.iter().zip(["0483:5740 A1", "0483:5740 B2"]) {
+      assert_eq!(session.info.device.as_deref(), Some(device));
+      assert_eq!(session.info.connection.as_deref(), Some("usb"));
+      assert_eq!(session.info.tool_version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+      assert!(session.stop.is_some_and(|stop| stop >= session.start));
+    }
+    assert_eq!(sessions[0].info.firmware_version.as_deref(), Some("1.2.0"));
+    assert_eq!(sessions[1].info.firmware_version, None);
+    assert!(sessions[0].id != sessions[1].id);
+  }
+
+  #[test]
+  fn logs_without_sessions_have_none() {
+    let file = TempFile::new("sq3");
+    sqlite::open(file.path()).unwrap()
+      .execute("CREATE TABLE points (realtime_ns INTEGER, rpm REAL);").unwrap();
+    assert!(LogReader::open(file.path()).unwrap().sessions().unwrap().is_empty());
+  }
+
+  fn rpm_log() -> TempFile {
+    testing::write_log(&["rpm", "map"], &(0..10)
+      .map(|i| (i * 100, vec![FeedValue::Int(i as u32), FeedValue::Float(0.5)]))
+      .collect::<Vec<_>>())
+  }
+
+  fn at(ms: u64) -> SystemTime {
+    testing::log_start() + Duration::from_millis(ms)
+  }
+
+  #[test]
+  fn queries_channels_within_a_window() {
+    let log = rpm_log();
+    let reader = LogReader::open(log.path()).unwrap();
+    assert_eq!(reader.time_span().unwrap(), Some((at(0), at(900))));
+
+    let frames = reader.query().channels(&["rpm"]).start(at(200)).end(at(500)).frames().unwrap()
+      .map(|f| f.unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(frames.iter().map(|f| f.time()).collect::<Vec<_>>(), [at(200), at(300), at(400)]);
+    assert_eq!(frames[0].keys(), ["rpm"]);
+    assert_eq!(frames[0].values(), [FeedValue::Int(2)]);
+
+    assert!(reader.query().channels(&["tps"]).frames().is_err());
+  }
+
+  #[test]
+  fn seeks_both_ways() {
+    let log = rpm_log();
+    let reader = LogReader::open(log.path()).unwrap();
+    let mut frames = reader.query().start(at(100)).frames().unwrap();
+    frames.seek(at(650)).unwrap();
+    assert_eq!(frames.next().unwrap().unwrap().time(), at(700));
+    // Never before the start of the window
+    frames.seek(at(0)).unwrap();
+    assert_eq!(frames.next().unwrap().unwrap().time(), at(100));
+  }
+
+  #[test]
+  fn channel_names_are_quoted() {
+    let names = ["it's", "say \"hi\"", "a,b"];
+    let log = testing::write_log(&names, &[(0, vec![FeedValue::Int(1), FeedValue::Int(2), FeedValue::Int(3)])]);
+    let reader = LogReader::open(log.path()).unwrap();
+    assert_eq!(reader.channels().iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), names);
+    let frame = reader.query().channels(&names[1..]).frames().unwrap().next().unwrap().unwrap();
+    assert_eq!(frame.values(), [FeedValue::Int(2), FeedValue::Int(3)]);
+  }
+
+  #[test]
+  fn points_are_indexed_by_time() {
+    let log = rpm_log();
+    let reader = LogReader::open(log.path()).unwrap();
+    let mut stmt = reader.conn
+      .prepare("SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'points_realtime'").unwrap();
+    assert_eq!(stmt.next().unwrap(), sqlite::State::Row);
+  }
+}
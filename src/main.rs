@@ -1,18 +1,19 @@
-use viaems::{self, interface, connection};
+use viaems::{self, connection};
 
 use clap::{Parser, Subcommand};
+#[allow(clippy::single_component_path_imports)]
 use ctrlc;
-use std::sync::mpsc;
-use std::time::{Duration, Instant, SystemTime};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
 struct Args {
 #[command(subcommand)]
   command: CliCommands,
-#[arg(short = 's', long, default_value = "127.0.0.1:5556")]
-  udpsrc: String,
-#[arg(short = 'd', long, default_value = "127.0.0.1:5555")]
-  udpdest: String,
+  /// usb[:VID:PID[:SERIAL]], udp://LOCAL->REMOTE, tcp://HOST:PORT,
+  /// serial:PORT, replay:LOG or sim
+#[arg(long, global = true, default_value = "usb")]
+  connection: connection::ConnectionUri,
 }
 
 #[derive(Subcommand, Debug)]
@@ -22,90 +23,877 @@ enum CliCommands {
     filename: String, 
   },
   Bootloader,
+  /// Convert a recorded log for other tools
+  Export {
+    input: String,
+#[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+    /// Defaults to stdout
+#[arg(short, long)]
+    output: Option<String>,
+    /// Comma separated, defaults to all
+#[arg(long, value_delimiter = ',')]
+    channels: Vec<String>,
+    /// Seconds from the start of the log
+#[arg(long)]
+    start: Option<f64>,
+#[arg(long)]
+    end: Option<f64>,
+    /// Write Unix timestamps rather than seconds from the start of the log
+#[arg(long)]
+    absolute_time: bool,
+    /// Resample to this rate in Hz
+#[arg(long)]
+    resample: Option<f64>,
+    /// Units for a channel as name=unit, for formats that carry them
+#[arg(long = "unit", value_parser = parse_unit)]
+    units: Vec<(String, String)>,
+  },
+  /// Print live feed values
+  Watch {
+    /// Channel names or glob patterns such as 'sensor.*', defaults to all
+    channels: Vec<String>,
+#[arg(long, value_enum, default_value_t = WatchFormat::Table)]
+    format: WatchFormat,
+    /// Table refreshes per second
+#[arg(long, default_value_t = 10.0)]
+    rate: f64,
+  },
+  /// Measure the round trip time to the device
+  Ping {
+#[arg(short = 'c', long, default_value_t = 5)]
+    count: u32,
+    /// Milliseconds between pings
+#[arg(long, default_value_t = 200)]
+    interval: u64,
+    /// Print a JSON summary
+#[arg(long)]
+    json: bool,
+  },
+  /// Show the device's configuration structure
+  Structure {
+    /// Print the structure as JSON
+#[arg(long)]
+    json: bool,
+  },
+  /// Read a configuration value
+  Get {
+    /// e.g. outputs[3].angle
+    path: viaems::interface::StructurePath,
+    /// Print the value as JSON
+#[arg(long)]
+    json: bool,
+  },
+  /// Change a configuration value
+  Set {
+    /// e.g. outputs[3].angle
+    path: viaems::interface::StructurePath,
+    /// Read as the same type as the current value, or as JSON for
+    /// compound values
+    value: String,
+    /// Print the old and new values as JSON
+#[arg(long)]
+    json: bool,
+  },
+  /// Back up or restore the device's configuration
+  Config {
+#[command(subcommand)]
+    command: ConfigCommands,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommands {
+  /// Write out every configuration value
+  Dump {
+    /// Defaults to stdout
+#[arg(short, long)]
+    output: Option<String>,
+#[arg(long, value_enum, default_value_t = ConfigFormat::Json)]
+    format: ConfigFormat,
+  },
+  /// Check a saved configuration against the device and write it
+  Load {
+    input: String,
+#[arg(long, value_enum, default_value_t = ConfigFormat::Json)]
+    format: ConfigFormat,
+    /// Show what would change without changing it
+#[arg(long)]
+    dry_run: bool,
+  },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ConfigFormat {
+  Json,
+  Cbor,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum WatchFormat {
+  /// The latest values, one row per refresh
+  Table,
+  /// Every frame as a JSON object per line
+  Jsonl,
+  /// Every frame as a CSV row
+  Csv,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ExportFormat {
+  Csv,
+  Mlg,
+  Mdf4,
+}
+
+fn parse_unit(arg: &str) -> Result<(String, String), String> {
+  arg.split_once('=')
+    .map(|(name, unit)| (name.to_string(), unit.to_string()))
+    .ok_or_else(|| format!("expected name=unit, got {arg}"))
 }
 
 
 fn main() {
   let args = Args::parse();
-  match args.command {
-    CliCommands::Record{filename} => record(&filename, &args.udpsrc, &args.udpdest),
+  let result = match args.command {
+    CliCommands::Record{filename} => record(&filename, &args.connection),
     CliCommands::Bootloader => bootloader(),
+    CliCommands::Export{input, format, output, channels, start, end, absolute_time, resample, units} => {
+      let options = viaems::export::ExportOptions{
+        channels: if channels.is_empty() { None } else { Some(channels) },
+        time: if absolute_time { viaems::export::TimeFormat::Absolute } else { viaems::export::TimeFormat::Relative },
+        resample,
+        units: units.into_iter().collect(),
+        ..Default::default()
+      };
+      export(&input, format, output.as_deref(), options, start, end)
+    },
+    CliCommands::Watch{channels, format, rate} => watch(&args.connection, &channels, format, rate),
+    CliCommands::Ping{count, interval, json} =>
+      ping(&args.connection, count, Duration::from_millis(interval), json),
+    CliCommands::Structure{json} => structure(&args.connection, json),
+    CliCommands::Get{path, json} => get(&args.connection, path, json),
+    CliCommands::Set{path, value, json} => set(&args.connection, path, &value, json),
+    CliCommands::Config{command: ConfigCommands::Dump{output, format}} =>
+      config_dump(&args.connection, output.as_deref(), format),
+    CliCommands::Config{command: ConfigCommands::Load{input, format, dry_run}} =>
+      config_load(&args.connection, &input, format, dry_run),
+  };
+  if let Err(e) = result {
+    eprintln!("{e}");
+    std::process::exit(1);
   }
 
 }
 
-fn bootloader() {
+fn bootloader() -> Result<(), viaems::Error> {
+  Ok(())
 }
     
 
+fn export(input: &str, format: ExportFormat, output: Option<&str>,
+          mut options: viaems::export::ExportOptions,
+          start: Option<f64>, end: Option<f64>) -> Result<(), viaems::Error> {
+  let reader = viaems::LogReader::open(input)?;
+  if let Some((first, _)) = reader.time_span()? {
+    let offset = |secs: f64| Duration::try_from_secs_f64(secs)
+      .map(|d| first + d)
+      .map_err(|_| viaems::Error::Other(format!("invalid time offset {secs}")));
+    options.start = start.map(offset).transpose()?;
+    options.end = end.map(offset).transpose()?;
+  }
+  if let ExportFormat::Mdf4 = format {
+    let Some(path) = output else {
+      return Err(viaems::Error::Other("MDF4 export needs an --output file".to_string()));
+    };
+    return viaems::export::export_mdf4(&reader, &options, std::fs::File::create(path)?);
+  }
+  let out : Box<dyn std::io::Write> = match output {
+    Some(path) => Box::new(std::fs::File::create(path)?),
+    None => Box::new(std::io::stdout().lock()),
+  };
+  match format {
+    ExportFormat::Csv => viaems::export::export_csv(&reader, &options, out),
+    ExportFormat::Mlg => viaems::export::export_mlg(&reader, &options, out),
+    ExportFormat::Mdf4 => unreachable!(),
+  }
+}
+
+// How long to wait for each request, including resends
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// stdout carries the results, so status goes to stderr. Link changes are
+// only worth showing to commands that keep running.
+fn open_device(uri: &connection::ConnectionUri, show_info: bool) -> Result<viaems::Manager, viaems::Error> {
+  let g = viaems::Manager::new(uri.open()?);
+  g.on_status(move |status: &viaems::Status| match status {
+    // Also returned to whoever made the request
+    viaems::Status::CommandTimeout{..} => (),
+    status => match status_message(status) {
+      Some(StatusMsg::Info(msg)) if show_info => eprintln!("{msg}"),
+      Some(StatusMsg::Warning(e)) => eprintln!("{e}"),
+      _ => (),
+    },
+  });
+  Ok(g)
+}
+
+fn ping(uri: &connection::ConnectionUri, count: u32, interval: Duration, json: bool) -> Result<(), viaems::Error> {
+  let g = open_device(uri, false)?;
+  // A resent ping would be timed from the first attempt
+  g.set_command_options(viaems::CommandOptions{timeout: REQUEST_TIMEOUT, retries: 0});
+  let mut times = vec![];
+  for i in 0..count {
+    if i > 0 {
+      std::thread::sleep(interval);
+    }
+    let sent = Instant::now();
+    match g.request_blocking(viaems::interface::RequestMessage::Ping{id: 0}, REQUEST_TIMEOUT) {
+      Ok(_) => {
+        let ms = sent.elapsed().as_secs_f64() * 1000.0;
+        if !json {
+          println!("Reply {}: {ms:.2} ms", i + 1);
+        }
+        times.push(ms);
+      },
+      Err(viaems::Error::Command(viaems::CommandError::Timeout{..})) => if !json {
+        println!("Ping {} timed out", i + 1);
+      },
+      Err(e) => return Err(e),
+    }
+  }
+
+  let received = times.len();
+  let lost = (count as usize - received) as f64 / count.max(1) as f64 * 100.0;
+  let stats = (!times.is_empty()).then(|| {
+    let min = times.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = times.iter().copied().fold(0.0, f64::max);
+    let avg = times.iter().sum::<f64>() / received as f64;
+    let stddev = (times.iter().map(|t| (t - avg).powi(2)).sum::<f64>() / received as f64).sqrt();
+    (min, avg, max, stddev)
+  });
+  if json {
+    let mut summary = serde_json::json!({
+      "sent": count,
+      "received": received,
+      "loss_percent": lost,
+      "times_ms": times,
+    });
+    if let Some((min, avg, max, stddev)) = stats {
+      summary["min_ms"] = min.into();
+      summary["avg_ms"] = avg.into();
+      summary["max_ms"] = max.into();
+      summary["stddev_ms"] = stddev.into();
+    }
+    println!("{summary}");
+  } else {
+    println!("{count} sent, {received} received, {lost:.0}% lost");
+    if let Some((min, avg, max, stddev)) = stats {
+      println!("min/avg/max/stddev = {min:.2}/{avg:.2}/{max:.2}/{stddev:.2} ms");
+    }
+  }
+  Ok(())
+}
+
+// One line per node, children indented beneath their parent
+fn print_structure(node: &viaems::interface::ResponseValue, name: &str, depth: usize) {
+  use viaems::interface::ResponseValue;
+  let indent = "  ".repeat(depth);
+  match node {
+    ResponseValue::Map(fields) => {
+      println!("{indent}{name}");
+      for (field, child) in viaems::sorted_fields(fields) {
+        print_structure(child, field, depth + 1);
+      }
+    },
+    ResponseValue::Array(elements) => {
+      println!("{indent}{name}");
+      for (i, child) in elements.iter().enumerate() {
+        print_structure(child, &format!("[{i}]"), depth + 1);
+      }
+    },
+    ResponseValue::Leaf(leaf) => println!("{indent}{name} ({}): {}", leaf.leaf_type, leaf.description),
+    other => println!("{indent}{name}: {}", serde_json::to_string(other).unwrap_or_default()),
+  }
+}
+
+fn structure(uri: &connection::ConnectionUri, json: bool) -> Result<(), viaems::Error> {
+  let g = open_device(uri, false)?;
+  let structure = g.request_blocking(viaems::interface::RequestMessage::Structure{id: 0}, REQUEST_TIMEOUT)?;
+  if json {
+    println!("{}", serde_json::to_string_pretty(&structure)?);
+  } else {
+    match &structure {
+      viaems::interface::ResponseValue::Map(fields) => for (field, child) in viaems::sorted_fields(fields) {
+        print_structure(child, field, 0);
+      },
+      other => print_structure(other, "", 0),
+    }
+  }
+  Ok(())
+}
+
+// Scalars as plain text, anything else as JSON
+fn format_value(value: &viaems::interface::ResponseValue) -> Result<String, viaems::Error> {
+  use viaems::interface::ResponseValue;
+  Ok(match value {
+    ResponseValue::Str(s) => s.clone(),
+    ResponseValue::Int(v) => v.to_string(),
+    ResponseValue::Float(v) => v.to_string(),
+    ResponseValue::Bool(v) => v.to_string(),
+    other => serde_json::to_string_pretty(other)?,
+  })
+}
+
+fn get(uri: &connection::ConnectionUri, path: viaems::interface::StructurePath, json: bool) -> Result<(), viaems::Error> {
+  let g = open_device(uri, false)?;
+  let value = g.request_blocking(viaems::interface::RequestMessage::Get{id: 0, path}, REQUEST_TIMEOUT)?;
+  if json {
+    println!("{}", serde_json::to_string(&value)?);
+  } else {
+    println!("{}", format_value(&value)?);
+  }
+  Ok(())
+}
+
+// Parses `text` as the same kind of value as `current`, so that e.g. `10`
+// is sent as an integer or a float depending on what the device holds
+fn parse_value(text: &str, current: &viaems::interface::ResponseValue)
+  -> Result<viaems::interface::ResponseValue, viaems::Error> {
+  use viaems::interface::ResponseValue;
+  let invalid = |e: &dyn std::fmt::Display| viaems::Error::Other(format!("invalid value {text}: {e}"));
+  Ok(match current {
+    ResponseValue::Str(_) => ResponseValue::Str(text.to_string()),
+    ResponseValue::Int(_) => ResponseValue::Int(text.parse().map_err(|e| invalid(&e))?),
+    ResponseValue::Float(_) => ResponseValue::Float(text.parse().map_err(|e| invalid(&e))?),
+    ResponseValue::Bool(_) => ResponseValue::Bool(text.parse().map_err(|e| invalid(&e))?),
+    _ => serde_json::from_str(text).map_err(|e| invalid(&e))?,
+  })
+}
+
+fn set(uri: &connection::ConnectionUri, path: viaems::interface::StructurePath, text: &str, json: bool) -> Result<(), viaems::Error> {
+  use viaems::interface::RequestMessage;
+  let g = open_device(uri, false)?;
+  let old = g.request_blocking(RequestMessage::Get{id: 0, path: path.clone()}, REQUEST_TIMEOUT)?;
+  let value = parse_value(text, &old)?;
+  let new = g.request_blocking(RequestMessage::Set{id: 0, path: path.clone(), value}, REQUEST_TIMEOUT)?;
+  if json {
+    println!("{}", serde_json::json!({"path": path.to_string(), "old": old, "new": new}));
+  } else {
+    println!("{path}: {} -> {}", format_value(&old)?, format_value(&new)?);
+  }
+  Ok(())
+}
+
+fn config_dump(uri: &connection::ConnectionUri, output: Option<&str>, format: ConfigFormat)
+  -> Result<(), viaems::Error> {
+  let g = open_device(uri, false)?;
+  let config = g.fetch_config(REQUEST_TIMEOUT)?;
+  for (path, value) in config.leaves() {
+    if let viaems::interface::ResponseValue::Error(e) = value {
+      eprintln!("{path}: could not be read: {}", e.error);
+    }
+  }
+  let mut out : Box<dyn std::io::Write> = match output {
+    Some(path) => Box::new(std::fs::File::create(path)?),
+    None => Box::new(std::io::stdout().lock()),
+  };
+  // Converted first so that maps are written in sorted order, keeping dumps
+  // of the same tune identical
+  match format {
+    ConfigFormat::Json => {
+      serde_json::to_writer_pretty(&mut out, &serde_json::to_value(&config.values)?)?;
+      writeln!(out)?;
+    },
+    ConfigFormat::Cbor => serde_cbor::to_writer(&mut out, &serde_cbor::value::to_value(&config.values)?)?,
+  }
+  out.flush()?;
+  Ok(())
+}
+
+fn config_load(uri: &connection::ConnectionUri, input: &str, format: ConfigFormat, dry_run: bool)
+  -> Result<(), viaems::Error> {
+  let file = std::io::BufReader::new(std::fs::File::open(input)?);
+  let wanted : viaems::interface::ResponseValue = match format {
+    ConfigFormat::Json => serde_json::from_reader(file)?,
+    ConfigFormat::Cbor => serde_cbor::from_reader(file)?,
+  };
+  let g = open_device(uri, false)?;
+  let comparison = g.fetch_config(REQUEST_TIMEOUT)?.compare(&wanted);
+  let show = |value: &viaems::interface::ResponseValue| serde_json::to_string(value).unwrap_or_default();
+
+  if !comparison.mismatches.is_empty() {
+    for (path, problem) in &comparison.mismatches {
+      println!("{path}: {problem}");
+    }
+    return Err(viaems::Error::Other(format!(
+      "{input} doesn't match the device's configuration in {} places, nothing was changed",
+      comparison.mismatches.len())));
+  }
+  for path in &comparison.missing {
+    println!("{path}: not in {input}, left unchanged");
+  }
+
+  let mut failed = 0;
+  for change in &comparison.changes {
+    if dry_run {
+      println!("{}: {} -> {}", change.path, show(&change.current), show(&change.wanted));
+      continue;
+    }
+    let set = viaems::interface::RequestMessage::Set{id: 0, path: change.path.clone(), value: change.wanted.clone()};
+    match g.request_blocking(set, REQUEST_TIMEOUT) {
+      Ok(new) => println!("{}: {} -> {}", change.path, show(&change.current), show(&new)),
+      Err(e) => {
+        println!("{}: failed to set {}: {e}", change.path, show(&change.wanted));
+        failed += 1;
+      },
+    }
+  }
+
+  let changed = comparison.changes.len() - failed;
+  if dry_run {
+    println!("{changed} would change, {} unchanged", comparison.unchanged);
+  } else {
+    println!("{changed} changed, {} unchanged, {failed} failed", comparison.unchanged);
+  }
+  if failed > 0 {
+    return Err(viaems::Error::Other(format!("{failed} values could not be set")));
+  }
+  Ok(())
+}
+
+// The channels matching `patterns`, in the order of the first pattern each
+// matches, or every channel if there are no patterns
+fn select_channels(schema: &viaems::FeedSchema, patterns: &[glob::Pattern])
+  -> Vec<(String, viaems::FeedIndex)> {
+  let mut selected : Vec<(String, viaems::FeedIndex)> = vec![];
+  if patterns.is_empty() {
+    selected.extend(schema.keys().iter()
+      .filter_map(|key| schema.index_of(key).map(|index| (key.clone(), index))));
+  }
+  for pattern in patterns {
+    for key in schema.keys() {
+      if pattern.matches(key) && !selected.iter().any(|(name, _)| name == key) {
+        selected.extend(schema.index_of(key).map(|index| (key.clone(), index)));
+      }
+    }
+  }
+  selected
+}
+
+fn unix_seconds(time: std::time::SystemTime) -> f64 {
+  time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+fn watch(uri: &connection::ConnectionUri, patterns: &[String], format: WatchFormat, rate: f64) -> Result<(), viaems::Error> {
+  let patterns = patterns.iter()
+    .map(|p| glob::Pattern::new(p)
+      .map_err(|e| viaems::Error::Other(format!("invalid channel pattern {p}: {e}"))))
+    .collect::<Result<Vec<_>, _>>()?;
+  let period = Duration::try_from_secs_f64(1.0 / rate).ok()
+    .filter(|period| !period.is_zero())
+    .ok_or_else(|| viaems::Error::Other(format!("invalid refresh rate {rate}")))?;
+
+  let g = open_device(uri, true)?;
+  let feed = g.subscribe(Default::default());
+
+  let running = Arc::new(std::sync::atomic::AtomicBool::new(true));
+  ctrlc::set_handler({
+    let running = running.clone();
+    move || running.store(false, std::sync::atomic::Ordering::Relaxed)
+  }).map_err(|e| viaems::Error::Other(e.to_string()))?;
+
+  match watch_loop(&feed, &patterns, format, period, &running, std::io::stdout().lock()) {
+    // The reader went away, e.g. piped into head
+    Err(viaems::Error::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+    result => result,
+  }
+}
+
+// Where watch gets its frames from, normally a feed subscription
+trait FrameSource {
+  fn recv_timeout(&self, timeout: Duration) -> Result<viaems::FeedFrame, mpsc::RecvTimeoutError>;
+  fn is_empty(&self) -> bool;
+}
+
+impl FrameSource for viaems::FeedSubscription {
+  fn recv_timeout(&self, timeout: Duration) -> Result<viaems::FeedFrame, mpsc::RecvTimeoutError> {
+    viaems::FeedSubscription::recv_timeout(self, timeout)
+  }
+
+  fn is_empty(&self) -> bool {
+    viaems::FeedSubscription::is_empty(self)
+  }
+}
+
+fn watch_loop(feed: &impl FrameSource, patterns: &[glob::Pattern], format: WatchFormat,
+              period: Duration, running: &std::sync::atomic::AtomicBool, out: impl std::io::Write)
+  -> Result<(), viaems::Error> {
+  use std::io::Write;
+  let mut out = std::io::BufWriter::new(out);
+  let mut schema : Option<Arc<viaems::FeedSchema>> = None;
+  let mut selected = vec![];
+  let mut widths : Vec<usize> = vec![];
+  let mut latest : Option<viaems::FeedFrame> = None;
+  let mut next_refresh = Instant::now();
+
+  while running.load(std::sync::atomic::Ordering::Relaxed) {
+    let timeout = match format {
+      WatchFormat::Table => next_refresh.saturating_duration_since(Instant::now()),
+      // Short enough to notice ctrl-c promptly
+      WatchFormat::Jsonl | WatchFormat::Csv => Duration::from_millis(100),
+    };
+    let frame = match feed.recv_timeout(timeout) {
+      Ok(frame) => Some(frame),
+      Err(mpsc::RecvTimeoutError::Timeout) => None,
+      Err(mpsc::RecvTimeoutError::Disconnected) => break,
+    };
+
+    if let Some(frame) = &frame {
+      if !schema.as_ref().is_some_and(|s| Arc::ptr_eq(s, frame.schema())) {
+        selected = select_channels(frame.schema(), patterns);
+        for pattern in patterns {
+          if !frame.keys().iter().any(|key| pattern.matches(key)) {
+            eprintln!("No channel matches {pattern}");
+          }
+        }
+        // A new header each time the layout changes
+        match format {
+          WatchFormat::Table => {
+            widths = selected.iter().map(|(name, _)| name.len().max(10)).collect();
+            let header : Vec<String> = selected.iter().zip(&widths)
+              .map(|((name, _), width)| format!("{name:>width$}"))
+              .collect();
+            writeln!(out, "{}", header.join(" "))?;
+          },
+          WatchFormat::Csv => {
+            write!(out, "time")?;
+            for (name, _) in &selected {
+              write!(out, ",{}", viaems::export::csv_field(name))?;
+            }
+            writeln!(out)?;
+          },
+          WatchFormat::Jsonl => (),
+        }
+        schema = Some(frame.schema().clone());
+      }
+    }
+
+    match format {
+      WatchFormat::Table => {
+        if frame.is_some() {
+          latest = frame;
+        }
+        if Instant::now() < next_refresh {
+          continue;
+        }
+        // Only printed when something new has arrived, so a stalled feed
+        // stands out
+        if let Some(frame) = latest.take() {
+          let row : Vec<String> = selected.iter().zip(&widths)
+            .map(|((_, index), width)| match frame.at(*index) {
+              Some(viaems::interface::FeedValue::Int(v)) => format!("{v:>width$}"),
+              Some(viaems::interface::FeedValue::Float(v)) => format!("{v:>width$.3}"),
+              None => format!("{:>width$}", "-"),
+            })
+            .collect();
+          writeln!(out, "{}", row.join(" "))?;
+          out.flush()?;
+        }
+        next_refresh = (next_refresh + period).max(Instant::now());
+      },
+      WatchFormat::Jsonl => if let Some(frame) = frame {
+        write!(out, "{{\"time\":{:.6}", unix_seconds(frame.time()))?;
+        for (name, index) in &selected {
+          write!(out, ",{}:", serde_json::Value::from(name.as_str()))?;
+          match frame.at(*index) {
+            Some(viaems::interface::FeedValue::Int(v)) => write!(out, "{v}")?,
+            Some(viaems::interface::FeedValue::Float(v)) if v.is_finite() => write!(out, "{v}")?,
+            _ => write!(out, "null")?,
+          }
+        }
+        writeln!(out, "}}")?;
+      },
+      WatchFormat::Csv => if let Some(frame) = frame {
+        write!(out, "{:.6}", unix_seconds(frame.time()))?;
+        for (_, index) in &selected {
+          match frame.at(*index) {
+            Some(viaems::interface::FeedValue::Int(v)) => write!(out, ",{v}")?,
+            Some(viaems::interface::FeedValue::Float(v)) if !v.is_nan() => write!(out, ",{v}")?,
+            _ => write!(out, ",")?,
+          }
+        }
+        writeln!(out)?;
+      },
+    }
+    // Batch writes while frames are queued up, but don't hold back the
+    // last one
+    if feed.is_empty() {
+      out.flush()?;
+    }
+  }
+  out.flush()?;
+  Ok(())
+}
+
 enum StatusMsg {
     Terminate,
+    Connected,
+    // The first feed point arrived, so the device is talking even if its
+    // transport doesn't report the link
+    FeedStarted,
     FeedCount{count: u64, rate: f64},
+    Info(String),
+    Warning(viaems::Error),
+    Failed(viaems::Error),
+}
+
+fn status_message(status: &viaems::Status) -> Option<StatusMsg> {
+  Some(match status {
+    viaems::Status::Link(connection::LinkStatus::Connected) => StatusMsg::Connected,
+    viaems::Status::Link(connection::LinkStatus::Disconnected) =>
+      StatusMsg::Info("Device disconnected".to_string()),
+    viaems::Status::Link(connection::LinkStatus::Reconnecting) =>
+      StatusMsg::Info("Reconnecting to device".to_string()),
+    viaems::Status::DescriptionChanged(schema) =>
+      StatusMsg::Info(format!("Feed layout changed: {} channels", schema.len())),
+    viaems::Status::CommandTimeout{id, attempts} =>
+      StatusMsg::Warning(viaems::CommandError::Timeout{id: *id, attempts: *attempts}.into()),
+    viaems::Status::DecodeError(e) | viaems::Status::Error(e) =>
+      StatusMsg::Warning(viaems::Error::Other(e.to_string())),
+    // Counted in Manager::stats
+    viaems::Status::FeedDropped(_) | viaems::Status::UnmatchedResponse{..} => return None,
+  })
 }
 
-fn record(filename: &str, udpsrc: &str, udpdest: &str) {
-//    let conn = Box::new(connection::UdpConnection::new(udpsrc, udpdest));
-    let conn = Box::new(connection::UsbConnection::new());
+fn record(filename: &str, uri: &connection::ConnectionUri) -> Result<(), viaems::Error> {
+    let mut session = viaems::SessionInfo::new(&uri.to_string());
+    // The log is only created once the first feed arrives, by which time
+    // the device has been opened
+    let (conn, opened): (Box<dyn connection::Connection + Send>, _) = match uri.usb_builder() {
+      Some(builder) => {
+        let usb = builder.build()?;
+        let opened = usb.opened_device();
+        (Box::new(usb), Some(opened))
+      },
+      None => (uri.open()?, None),
+    };
     let g = viaems::Manager::new(conn);
     let (status_chan_tx, status_chan) = mpsc::channel::<StatusMsg>();
 
-    g.on_feed({
+    g.on_status({
+      let status_chan_tx = status_chan_tx.clone();
+      move |status: &viaems::Status| {
+        if let Some(msg) = status_message(status) {
+          let _ = status_chan_tx.send(msg);
+        }
+      }
+    });
+
+    // Large enough to ride out the log writer stalling for a few seconds
+    let feed = g.subscribe(viaems::SubscribeOptions{capacity: 16384, ..Default::default()});
+    let (config_tx, config_rx) = mpsc::channel::<viaems::ConfigSnapshot>();
+    let recorder = std::thread::spawn({
       let status_chan_tx = status_chan_tx.clone();
-      let mut writer : Option<viaems::LogFeedWriter> = None;
       let filename = filename.to_owned();
+      move || {
+      let mut writer : Option<viaems::LogFeedWriter> = None;
+      let mut schema : Option<Arc<viaems::FeedSchema>> = None;
       let mut total_count = 0;
       let mut this_count = 0;
+      let mut dropped = 0;
       let mut time_of_last_msg = Instant::now();
-      move |time: SystemTime, keys: &Vec<String>, vals: &Vec<interface::FeedValue>| {
-        if writer.is_none() {
-          writer = Some(viaems::LogFeedWriter::new(&filename, keys.clone()));
+      for frame in feed.iter() {
+        match &writer {
+          None => {
+            if let Some(d) = opened.as_ref().and_then(|o| o.get()) {
+              session.device = Some(format!("{:04x}:{:04x} {}", d.vendor_id, d.product_id, d.serial.unwrap_or_default()));
+              session.firmware_version = Some(d.device_version);
+            }
+            match viaems::LogFeedWriter::new(&filename, frame.keys().to_vec(), session.clone()) {
+              Ok(w) => {
+                writer = Some(w);
+                let _ = status_chan_tx.send(StatusMsg::FeedStarted);
+              },
+              Err(e) => {
+                let _ = status_chan_tx.send(StatusMsg::Failed(e));
+                return;
+              },
+            }
+          },
+          Some(w) if !schema.as_ref().is_some_and(|s| Arc::ptr_eq(s, frame.schema())) => {
+            if let Err(e) = w.set_keys(frame.keys().to_vec()) {
+              let _ = status_chan_tx.send(StatusMsg::Failed(e));
+            }
+          },
+          Some(_) => (),
         }
+        schema = Some(frame.schema().clone());
         if let Some(w) = &mut writer {
-          w.add(time, vals.clone());
+          for config in config_rx.try_iter() {
+            if let Err(e) = w.add_config(config) {
+              let _ = status_chan_tx.send(StatusMsg::Warning(e));
+            }
+          }
+          if let Err(e) = w.add(frame.time(), frame.into_values()) {
+            let _ = status_chan_tx.send(StatusMsg::Failed(e));
+          }
+          for e in w.take_errors() {
+            let _ = status_chan_tx.send(StatusMsg::Warning(e));
+          }
         }
         this_count += 1;
         let duration = Instant::now() - time_of_last_msg;
         if duration >= Duration::from_secs(1) {
             total_count += this_count;
-            status_chan_tx.send(StatusMsg::FeedCount{
+            let _ = status_chan_tx.send(StatusMsg::FeedCount{
                 count: total_count,
                 rate: this_count as f64 / duration.as_secs_f64(),
-            }).unwrap();
+            });
+            if feed.dropped() > dropped {
+              let _ = status_chan_tx.send(StatusMsg::Info(
+                  format!("Log writer fell behind, {} feed points lost", feed.dropped() - dropped)));
+              dropped = feed.dropped();
+            }
             this_count = 0;
             time_of_last_msg += duration;
         }
-
+      }
     }});
 
-//    let getcmd = interface::RequestMessage::Structure{id: 5};
-//    g.command(interface::Message::Request(getcmd),
-//      |resp: interface::ResponseValue| {
-//        println!("struct response: {:?}", resp);
-//       }
-//     );
-//
-//    for i in 1..=5 {
-//      let ping = interface::RequestMessage::Ping{id: i};
-//      g.command(interface::Message::Request(ping),
-//        |resp: interface::ResponseValue| {
-//          println!("ping response: {:?}", resp);
-//         }
-//       );
-//      std::thread::sleep(Duration::from_millis(500));
-//    }
-
-    ctrlc::set_handler(move || status_chan_tx.send(StatusMsg::Terminate).unwrap() ).unwrap();
-
-    loop {
+    ctrlc::set_handler(move || { let _ = status_chan_tx.send(StatusMsg::Terminate); })
+        .map_err(|e| viaems::Error::Other(e.to_string()))?;
+
+    // Saved with the session so the log records which tune produced it. The
+    // device may not be there yet, so this waits until it is and keeps
+    // trying until a snapshot is read.
+    let mut connected = false;
+    let mut config_saved = false;
+    let mut next_config_attempt = Instant::now();
+    let result = loop {
+        if connected && !config_saved && Instant::now() >= next_config_attempt {
+            match g.fetch_config(Duration::from_secs(1)) {
+                Ok(config) => {
+                    let _ = config_tx.send(config);
+                    config_saved = true;
+                },
+                Err(e) => {
+                    println!("Could not read the device configuration, retrying: {e}");
+                    next_config_attempt = Instant::now() + Duration::from_secs(5);
+                },
+            }
+        }
         match status_chan.recv_timeout(Duration::from_millis(1200)) {
-            Ok(StatusMsg::Terminate) => break,
+            Ok(StatusMsg::Terminate) => break Ok(()),
+            Ok(StatusMsg::Connected) => {
+                println!("Device connected");
+                connected = true;
+            },
+            Ok(StatusMsg::FeedStarted) => connected = true,
             Ok(StatusMsg::FeedCount{count, rate}) => {
                 println!("Connected! {} feed points received ({:.0}/s)", count, rate);
             },
+            Ok(StatusMsg::Info(msg)) => println!("{msg}"),
+            Ok(StatusMsg::Warning(e)) => println!("{e}"),
+            Ok(StatusMsg::Failed(e)) => break Err(e),
             Err(mpsc::RecvTimeoutError::Timeout) => {
-                println!("No new data");
+                let stats = g.stats();
+                println!("No new data ({} dropped feeds, {} decode errors)",
+                         stats.dropped_feeds, stats.decode_errors);
             }
-            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break Ok(()),
         }
+    };
+    // Stops the feed, letting the recorder finish writing what it has queued,
+    // even when recording failed
+    drop(g);
+    let _ = recorder.join();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use viaems::interface::FeedValue;
+
+  fn patterns(patterns: &[&str]) -> Vec<glob::Pattern> {
+    patterns.iter().map(|p| glob::Pattern::new(p).unwrap()).collect()
+  }
+
+  fn names(selected: &[(String, viaems::FeedIndex)]) -> Vec<&str> {
+    selected.iter().map(|(name, _)| name.as_str()).collect()
+  }
+
+  #[test]
+  fn selects_channels_in_pattern_order() {
+    let schema = viaems::FeedSchema::new(
+      ["rpm", "sensor.map", "sensor.iat", "advance"].iter().map(|k| k.to_string()).collect());
+    assert_eq!(names(&select_channels(&schema, &[])), ["rpm", "sensor.map", "sensor.iat", "advance"]);
+    assert_eq!(names(&select_channels(&schema, &patterns(&["advance", "sensor.*", "sensor.map", "tps"]))),
+               ["advance", "sensor.map", "sensor.iat"]);
+  }
+
+  // A fixed list of frames, then the end of the feed
+  impl FrameSource for std::cell::RefCell<std::collections::VecDeque<viaems::FeedFrame>> {
+    fn recv_timeout(&self, _: Duration) -> Result<viaems::FeedFrame, mpsc::RecvTimeoutError> {
+      self.borrow_mut().pop_front().ok_or(mpsc::RecvTimeoutError::Disconnected)
+    }
+
+    fn is_empty(&self) -> bool {
+      self.borrow().is_empty()
+    }
+  }
+
+  fn frames(keys: &[&str], rows: &[(u64, Vec<FeedValue>)]) -> std::cell::RefCell<std::collections::VecDeque<viaems::FeedFrame>> {
+    let schema = Arc::new(viaems::FeedSchema::new(keys.iter().map(|k| k.to_string()).collect()));
+    let start = std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    std::cell::RefCell::new(rows.iter()
+      .map(|(ms, values)| viaems::FeedFrame::new(start + Duration::from_millis(*ms), schema.clone(), values.clone()).unwrap())
+      .collect())
+  }
+
+  #[test]
+  fn watch_writes_csv_and_jsonl() {
+    let rows = [
+      (0, vec![FeedValue::Int(0), FeedValue::Float(0.0)]),
+      (100, vec![FeedValue::Int(800), FeedValue::Float(30.5)]),
+      (150, vec![FeedValue::Int(900), FeedValue::Float(f32::NAN)]),
+    ];
+    let running = std::sync::atomic::AtomicBool::new(true);
+    for (format, expected) in [
+      (WatchFormat::Csv, "time,sensor.map,rpm\n\
+        1700000000.000000,0,0\n\
+        1700000000.100000,30.5,800\n\
+        1700000000.150000,,900\n"),
+      (WatchFormat::Jsonl, "{\"time\":1700000000.000000,\"sensor.map\":0,\"rpm\":0}\n\
+        {\"time\":1700000000.100000,\"sensor.map\":30.5,\"rpm\":800}\n\
+        {\"time\":1700000000.150000,\"sensor.map\":null,\"rpm\":900}\n"),
+    ] {
+      let feed = frames(&["rpm", "sensor.map"], &rows);
+      let mut out = vec![];
+      watch_loop(&feed, &patterns(&["sensor.*", "rpm"]), format, Duration::from_millis(100), &running, &mut out).unwrap();
+      assert_eq!(String::from_utf8(out).unwrap(), expected);
     }
+  }
+
+  #[test]
+  fn parses_values_as_the_current_type() {
+    use viaems::interface::ResponseValue;
+    assert_eq!(parse_value("10", &ResponseValue::Int(1)).unwrap(), ResponseValue::Int(10));
+    assert_eq!(parse_value("10", &ResponseValue::Float(1.0)).unwrap(), ResponseValue::Float(10.0));
+    assert_eq!(parse_value("10", &ResponseValue::Str(String::new())).unwrap(), ResponseValue::Str("10".to_string()));
+    assert_eq!(parse_value("true", &ResponseValue::Bool(false)).unwrap(), ResponseValue::Bool(true));
+    assert_eq!(parse_value("[1, 2]", &ResponseValue::Array(vec![])).unwrap(),
+               ResponseValue::Array(vec![ResponseValue::Int(1), ResponseValue::Int(2)]));
+    assert!(parse_value("1.5", &ResponseValue::Int(1)).is_err());
+    assert!(parse_value("yes", &ResponseValue::Bool(false)).is_err());
+  }
+
+  #[test]
+  fn formats_scalars_as_text() {
+    use viaems::interface::ResponseValue;
+    assert_eq!(format_value(&ResponseValue::Str("tfi".to_string())).unwrap(), "tfi");
+    assert_eq!(format_value(&ResponseValue::Float(45.5)).unwrap(), "45.5");
+    assert_eq!(format_value(&ResponseValue::Array(vec![ResponseValue::Int(1)])).unwrap(), "[\n  1\n]");
+  }
 }
@@ -0,0 +1,74 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::interface;
+use crate::{CommandError, Error};
+
+type CommandResult = Result<interface::ResponseValue, CommandError>;
+
+enum Slot {
+  Pending(Option<Waker>),
+  Ready(Result<interface::ResponseValue, Error>),
+  Taken,
+}
+
+// Resolves to the device's response for a request queued with
+// `Manager::request`. It does not depend on any particular executor: the
+// Manager's receive thread wakes the task when the response (or a timeout)
+// arrives.
+pub struct ResponseFuture {
+  slot: Arc<Mutex<Slot>>,
+}
+
+// The half of a ResponseFuture that is handed to the command queue as the
+// callback. If it is dropped without completing, e.g. because the Manager
+// went away, the future resolves to CommandError::Disconnected.
+pub(crate) struct Completion {
+  slot: Arc<Mutex<Slot>>,
+}
+
+pub(crate) fn pair() -> (ResponseFuture, Completion) {
+  let slot = Arc::new(Mutex::new(Slot::Pending(None)));
+  (ResponseFuture { slot: slot.clone() }, Completion { slot })
+}
+
+impl Completion {
+  pub(crate) fn complete(self, result: CommandResult) {
+    self.fill(result);
+  }
+
+  fn fill(&self, result: CommandResult) {
+    let mut slot = self.slot.lock().unwrap();
+    if let Slot::Pending(waker) = &mut *slot {
+      let waker = waker.take();
+      *slot = Slot::Ready(result.map_err(Error::from));
+      if let Some(waker) = waker {
+        waker.wake();
+      }
+    }
+  }
+}
+
+impl Drop for Completion {
+  fn drop(&mut self) {
+    self.fill(Err(CommandError::Disconnected));
+  }
+}
+
+impl Future for ResponseFuture {
+  type Output = Result<interface::ResponseValue, Error>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let mut slot = self.slot.lock().unwrap();
+    match std::mem::replace(&mut *slot, Slot::Taken) {
+      Slot::Pending(_) => {
+        *slot = Slot::Pending(Some(cx.waker().clone()));
+        Poll::Pending
+      },
+      Slot::Ready(result) => Poll::Ready(result),
+      Slot::Taken => panic!("ResponseFuture polled after completion"),
+    }
+  }
+}
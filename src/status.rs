@@ -0,0 +1,46 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::connection::LinkStatus;
+use crate::{Error, FeedSchema};
+
+// Reported through `Manager::on_status` as the connection's health changes
+#[derive(Debug)]
+pub enum Status {
+  // Connections that don't report link changes themselves are considered
+  // connected once their first message arrives, and disconnected when they
+  // shut down.
+  Link(LinkStatus),
+  // The device described a feed layout different from the previous one
+  DescriptionChanged(Arc<FeedSchema>),
+  // Data that couldn't be decoded as a message was discarded
+  DecodeError(Error),
+  // A feed was discarded because no description for it had been received,
+  // or it didn't match the description's length
+  FeedDropped(Error),
+  // A command ran out of retries
+  CommandTimeout { id: u32, attempts: u32 },
+  // A response arrived for a request that isn't waiting for one, e.g. a
+  // late reply to a request that already timed out
+  UnmatchedResponse { id: u32 },
+  // Any other error the connection recovered from, e.g. a failed write
+  Error(Error),
+}
+
+// Running totals since the Manager was created, see `Manager::stats`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+  pub feeds: u64,
+  pub descriptions: u64,
+  pub description_changes: u64,
+  pub decode_errors: u64,
+  pub dropped_feeds: u64,
+  pub commands_sent: u64,
+  pub command_retries: u64,
+  pub command_timeouts: u64,
+  pub unmatched_responses: u64,
+  pub errors: u64,
+  // Times the link came back after being lost
+  pub reconnects: u64,
+  pub last_message: Option<SystemTime>,
+}
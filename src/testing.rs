@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use crate::interface::FeedValue;
+use crate::{LogFeedWriter, SessionInfo};
+
+// A path in the temp directory that is removed, along with sqlite's
+// journal files, when dropped
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(extension: &str) -> TempFile {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("viaems-test-{}-{}.{extension}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        TempFile { path: std::env::temp_dir().join(name) }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", self.path()));
+        }
+    }
+}
+
+// Start time of the logs written by `write_log`
+pub(crate) fn log_start() -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+}
+
+// Records `rows` of (milliseconds after `log_start()`, values) for `keys`
+pub(crate) fn write_log(keys: &[&str], rows: &[(u64, Vec<FeedValue>)]) -> TempFile {
+    let file = TempFile::new("sq3");
+    let keys = keys.iter().map(|k| k.to_string()).collect();
+    let writer = LogFeedWriter::new(file.path(), keys, SessionInfo::new("sim")).unwrap();
+    for (ms, values) in rows {
+        writer.add(log_start() + Duration::from_millis(*ms), values.clone()).unwrap();
+    }
+    drop(writer);
+    file
+}